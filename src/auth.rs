@@ -0,0 +1,197 @@
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// relay access tokens: the claims as base64url JSON, a dot, then a base64url HMAC-SHA256 of the
+// claims part under a secret shared by the relay and whoever mints the tokens
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    // may send offers, the producer
+    Publisher,
+    // may answer but not offer, a consumer
+    Viewer,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    pub room: String,
+    pub role: Role,
+    // seconds since the unix epoch after which the relay refuses the token
+    pub expires_at: u64,
+}
+
+impl Claims {
+    // claims that expire valid_for_secs from now
+    pub fn new(room: &str, role: Role, valid_for_secs: u64) -> Self {
+        Claims {
+            room: room.to_string(),
+            role,
+            expires_at: unix_time(SystemTime::now()).saturating_add(valid_for_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    // the relay wants a token and the client sent none
+    Missing,
+    // not two base64url parts, or the claims are not valid JSON
+    Malformed,
+    // signed with another secret, or altered since
+    BadSignature,
+    Expired,
+    // the token is for another room than the one joined
+    WrongRoom { room: String },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "this relay needs a token"),
+            AuthError::Malformed => write!(f, "malformed token"),
+            AuthError::BadSignature => write!(f, "token signature does not match"),
+            AuthError::Expired => write!(f, "token has expired"),
+            AuthError::WrongRoom { room } => write!(f, "token is for room {}", room),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+pub fn mint(secret: &[u8], claims: &Claims) -> String {
+    let json = serde_json::to_vec(claims).expect("claims always serialize");
+    let payload = URL_SAFE_NO_PAD.encode(json);
+    let signature = URL_SAFE_NO_PAD.encode(sign(secret, &payload).finalize().into_bytes());
+    format!("{}.{}", payload, signature)
+}
+
+// checks the signature before looking at the claims, and the expiry against now
+pub fn verify(secret: &[u8], token: &str, now: SystemTime) -> Result<Claims, AuthError> {
+    let (payload, signature) = token.split_once('.').ok_or(AuthError::Malformed)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| AuthError::Malformed)?;
+    sign(secret, payload)
+        .verify_slice(&signature)
+        .map_err(|_| AuthError::BadSignature)?;
+
+    let json = URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| AuthError::Malformed)?;
+    let claims: Claims = serde_json::from_slice(&json).map_err(|_| AuthError::Malformed)?;
+
+    if unix_time(now) >= claims.expires_at {
+        return Err(AuthError::Expired);
+    }
+    Ok(claims)
+}
+
+// the role a token grants in room
+pub fn authorize(secret: &[u8], room: &str, token: Option<&str>) -> Result<Role, AuthError> {
+    let token = token.ok_or(AuthError::Missing)?;
+    let claims = verify(secret, token, SystemTime::now())?;
+    if claims.room != room {
+        return Err(AuthError::WrongRoom { room: claims.room });
+    }
+    Ok(claims.role)
+}
+
+fn sign(secret: &[u8], payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"relay secret";
+
+    fn claims(expires_at: u64) -> Claims {
+        Claims {
+            room: "studio".to_string(),
+            role: Role::Viewer,
+            expires_at,
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn minted_token_verifies() {
+        let token = mint(SECRET, &claims(1000));
+        assert_eq!(verify(SECRET, &token, at(999)), Ok(claims(1000)));
+    }
+
+    #[test]
+    fn expired_token_is_refused() {
+        let token = mint(SECRET, &claims(1000));
+        assert_eq!(verify(SECRET, &token, at(1000)), Err(AuthError::Expired));
+    }
+
+    #[test]
+    fn other_secret_is_refused() {
+        let token = mint(b"another secret", &claims(1000));
+        assert_eq!(verify(SECRET, &token, at(0)), Err(AuthError::BadSignature));
+    }
+
+    #[test]
+    fn altered_claims_are_refused() {
+        let token = mint(SECRET, &claims(1000));
+        let (_, signature) = token.split_once('.').unwrap();
+        let mut publisher = claims(1000);
+        publisher.role = Role::Publisher;
+        let forged = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&publisher).unwrap()),
+            signature
+        );
+        assert_eq!(verify(SECRET, &forged, at(0)), Err(AuthError::BadSignature));
+    }
+
+    #[test]
+    fn token_only_admits_its_room() {
+        let token = mint(SECRET, &Claims::new("studio", Role::Publisher, 60));
+        assert_eq!(
+            authorize(SECRET, "studio", Some(&token)),
+            Ok(Role::Publisher)
+        );
+        assert_eq!(
+            authorize(SECRET, "lobby", Some(&token)),
+            Err(AuthError::WrongRoom {
+                room: "studio".to_string()
+            })
+        );
+        assert_eq!(authorize(SECRET, "studio", None), Err(AuthError::Missing));
+    }
+
+    #[test]
+    fn garbage_is_malformed() {
+        assert_eq!(
+            verify(SECRET, "not a token", at(0)),
+            Err(AuthError::Malformed)
+        );
+        assert_eq!(verify(SECRET, "a.b!", at(0)), Err(AuthError::Malformed));
+    }
+}
@@ -1,7 +1,15 @@
 #![allow(unused)]
 
-use livestream_build::{Signal, mediaconsumer::run_consumer_pipeline, peercomms::run_peer_socket};
-use std::thread;
+use livestream_build::{
+    Envelope,
+    config::Config,
+    mediaconsumer::{
+        AudioSamples, ConsumerOutput, FrameCallbacks, VideoFrame, run_consumer_pipeline,
+    },
+    signaling::{RelaySignaling, SignalingTransport},
+    unixsignals::{forward_recording_signals, forward_shutdown_signals},
+};
+use std::{process::ExitCode, thread};
 use tokio::sync::mpsc::channel;
 
 // PART 4
@@ -10,16 +18,90 @@ use tokio::sync::mpsc::channel;
 // Consumer will webrtcbin -> decode -> convert -> scale/resample -> sink
 
 #[tokio::main]
-pub async fn main() -> std::io::Result<()> {
-    let (send_to_tokio, tokio_recv) = channel::<Signal>(10);
-    let (send_to_gst, gst_recv) = channel::<Signal>(10);
+pub async fn main() -> ExitCode {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    let send_to_tokio = send_to_tokio.clone();
-    let send_to_gst = send_to_gst.clone();
+    let (send_to_tokio, tokio_recv) = channel::<Envelope>(10);
+    let (send_to_gst, gst_recv) = channel::<Envelope>(10);
 
-    thread::spawn(move || {
-        run_consumer_pipeline(send_to_tokio, gst_recv);
+    let output = if config.consumer.record_only {
+        ConsumerOutput::RecordOnly
+    } else if config.consumer.headless {
+        ConsumerOutput::Headless(logging_callbacks())
+    } else {
+        ConsumerOutput::Playback
+    };
+
+    let pipeline_config = config.clone();
+    let pipeline_thread = thread::spawn(move || {
+        run_consumer_pipeline(&pipeline_config, output, send_to_tokio, gst_recv)
     });
 
-    run_peer_socket(send_to_gst, tokio_recv).await
+    let recording_signals = tokio::spawn(forward_recording_signals(send_to_gst.clone()));
+    let shutdown_signals = tokio::spawn(forward_shutdown_signals(send_to_gst.clone()));
+
+    // returns once the pipeline has stopped, or stops the pipeline by dropping send_to_gst
+    let socket_result = RelaySignaling::new(config)
+        .run(send_to_gst, tokio_recv)
+        .await;
+    // their senders would keep the pipeline running
+    recording_signals.abort();
+    shutdown_signals.abort();
+    let pipeline_result = pipeline_thread
+        .join()
+        .expect("consumer pipeline thread panicked");
+
+    match (socket_result, pipeline_result) {
+        (Err(err), _) => {
+            eprintln!("signaling failed: {}", err);
+            ExitCode::FAILURE
+        }
+        (Ok(()), Err(err)) => {
+            eprintln!("consumer pipeline failed: {}", err);
+            ExitCode::FAILURE
+        }
+        (Ok(()), Ok(())) => ExitCode::SUCCESS,
+    }
+}
+
+// headless mode just reports what arrives, every 100th frame or audio buffer
+fn logging_callbacks() -> FrameCallbacks {
+    let mut video_frames: u64 = 0;
+    let mut audio_buffers: u64 = 0;
+
+    FrameCallbacks {
+        on_ice_connection_state: Box::new(|state| {
+            println!("ice connection state: {:?}", state);
+        }),
+        on_video_frame: Box::new(move |frame: VideoFrame| {
+            video_frames += 1;
+            if video_frames % 100 == 1 {
+                println!(
+                    "video frame {}: {:?}x{:?} pts {:?}",
+                    video_frames,
+                    frame.width(),
+                    frame.height(),
+                    frame.pts
+                );
+            }
+        }),
+        on_audio_samples: Box::new(move |samples: AudioSamples| {
+            audio_buffers += 1;
+            if audio_buffers % 100 == 1 {
+                println!(
+                    "audio buffer {}: {:?} Hz, {:?} channels, pts {:?}",
+                    audio_buffers,
+                    samples.rate(),
+                    samples.channels(),
+                    samples.pts
+                );
+            }
+        }),
+    }
 }
@@ -1,7 +1,13 @@
 #![allow(unused)]
 
-use livestream_build::{Signal, mediaproducer::run_producer_pipeline, peercomms::run_peer_socket};
-use std::thread;
+use livestream_build::{
+    Envelope,
+    config::Config,
+    mediaproducer::run_producer_pipeline,
+    signaling::{RelaySignaling, SignalingTransport},
+    unixsignals::{forward_recording_signals, forward_shutdown_signals},
+};
+use std::{process::ExitCode, thread};
 use tokio::sync::mpsc::channel;
 
 // PART 4
@@ -10,16 +16,45 @@ use tokio::sync::mpsc::channel;
 // Consumer will webrtcbin -> decode -> convert -> scale/resample -> sink
 
 #[tokio::main]
-pub async fn main() -> std::io::Result<()> {
-    let (send_to_tokio, tokio_recv) = channel::<Signal>(10);
-    let (send_to_gst, gst_recv) = channel::<Signal>(10);
+pub async fn main() -> ExitCode {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    let send_to_tokio = send_to_tokio.clone();
-    let send_to_gst = send_to_gst.clone();
+    let (send_to_tokio, tokio_recv) = channel::<Envelope>(10);
+    let (send_to_gst, gst_recv) = channel::<Envelope>(10);
 
-    thread::spawn(move || {
-        run_producer_pipeline(send_to_tokio, gst_recv);
-    });
+    let pipeline_config = config.clone();
+    let pipeline_thread =
+        thread::spawn(move || run_producer_pipeline(&pipeline_config, send_to_tokio, gst_recv));
 
-    run_peer_socket(send_to_gst, tokio_recv).await
+    let recording_signals = tokio::spawn(forward_recording_signals(send_to_gst.clone()));
+    let shutdown_signals = tokio::spawn(forward_shutdown_signals(send_to_gst.clone()));
+
+    // returns once the pipeline has stopped, or stops the pipeline by dropping send_to_gst
+    let socket_result = RelaySignaling::new(config)
+        .run(send_to_gst, tokio_recv)
+        .await;
+    // their senders would keep the pipeline running
+    recording_signals.abort();
+    shutdown_signals.abort();
+    let pipeline_result = pipeline_thread
+        .join()
+        .expect("producer pipeline thread panicked");
+
+    match (socket_result, pipeline_result) {
+        (Err(err), _) => {
+            eprintln!("signaling failed: {}", err);
+            ExitCode::FAILURE
+        }
+        (Ok(()), Err(err)) => {
+            eprintln!("producer pipeline failed: {}", err);
+            ExitCode::FAILURE
+        }
+        (Ok(()), Ok(())) => ExitCode::SUCCESS,
+    }
 }
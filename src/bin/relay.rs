@@ -1,94 +1,46 @@
-use std::net::SocketAddr;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::{TcpListener, TcpStream},
-    sync::broadcast::{self, Sender},
+use clap::Parser;
+use livestream_build::{
+    config::RelayCli,
+    relay::{RelayOptions, run_relay_until},
+    tls,
+    unixsignals::shutdown_signal,
 };
-
-const ADDRESS: &str = "0.0.0.0:8080";
-
-#[derive(Debug, Clone)]
-pub struct MsgType {
-    pub socket_addr: SocketAddr,
-    pub payload: String,
-}
+use tokio::net::TcpListener;
 
 #[tokio::main]
 pub async fn main() -> std::io::Result<()> {
-    let tcp_listener = TcpListener::bind(ADDRESS).await?;
-    println!("TcpListener binded to {}.", ADDRESS);
-
-    // channel shared by all clients
-    let (sender, _) = broadcast::channel::<MsgType>(10);
-
-    loop {
-        // accept incoming socket connections
-        let (tcp_stream, socket_addr) = tcp_listener.accept().await?;
-        println!(
-            "TcpListener accepted a connection. Client address: {}",
-            socket_addr
-        );
+    let cli = RelayCli::parse();
 
-        let sender: Sender<MsgType> = sender.clone();
-        tokio::spawn(async move {
-            let result = handle_client(tcp_stream, sender, socket_addr).await;
-            match result {
-                Ok(_) => println!("handle_client terminated gracefully"),
-                Err(error) => eprintln!("handle_client returned an error: {:?}", error),
-            }
-        });
+    let mut options = RelayOptions::default();
+    // clap makes sure neither comes without the other
+    if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+        let acceptor = tls::acceptor(cert, key).map_err(std::io::Error::other)?;
+        options.tls = Some(acceptor);
+    }
+    if let Some(secret) = &cli.auth_secret {
+        options.auth_secret = Some(secret.as_bytes().into());
+        println!("Clients need a token to join.");
     }
-}
-
-async fn handle_client(
-    mut tcp_stream: TcpStream,
-    sender: Sender<MsgType>,
-    socket_addr: SocketAddr,
-) -> std::io::Result<()> {
-    let mut receiver = sender.subscribe();
-
-    let (reader, writer) = tcp_stream.split();
-    let mut reader = BufReader::new(reader);
-    let mut writer = BufWriter::new(writer);
-
-    let mut incoming = String::new();
-
-    loop {
-        let sender = sender.clone();
-        tokio::select! {
-            // read from broadcast channel
-            result = receiver.recv() => {
-                match result {
-                    Ok(msg) => {
-                        if msg.socket_addr != socket_addr {
-                            writer.write_all(msg.payload.as_bytes()).await?;
-                            writer.flush().await?;
-                        }
-                    }
-                    Err(error) => eprintln!("Failed to read from broadcast channel: {:?}", error)
-                }
-            }
-
-            // read from socket
-            socket_read_result = reader.read_line(&mut incoming) => {
-                println!("Message received from this client's socket.");
-                let num_bytes_read: usize = socket_read_result?;
-                println!("{}", incoming);
-
-                if num_bytes_read == 0 {
-                    println!("No bytes read from socket. Client disconnected.");
-                    break;
-                }
-
-                let _ = sender.send(MsgType {
-                    socket_addr,
-                    payload: incoming.to_string(),
-                });
 
-                incoming.clear();
+    let tcp_listener = TcpListener::bind(&cli.listen).await?;
+    println!(
+        "TcpListener binded to {}{}.",
+        cli.listen,
+        if options.tls.is_some() {
+            " with TLS"
+        } else {
+            ""
+        }
+    );
+
+    let shutdown = async {
+        match shutdown_signal().await {
+            Ok(()) => println!("Shutting down."),
+            Err(err) => {
+                eprintln!("could not listen for Ctrl-C/SIGTERM: {}", err);
+                std::future::pending().await
             }
         }
-    }
-
-    Ok(())
+    };
+    run_relay_until(tcp_listener, options, shutdown).await
 }
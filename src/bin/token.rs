@@ -0,0 +1,12 @@
+use clap::Parser;
+use livestream_build::{
+    auth::{self, Claims},
+    config::TokenCli,
+};
+
+// prints a token for the relay started with the same secret
+pub fn main() {
+    let cli = TokenCli::parse();
+    let claims = Claims::new(&cli.room, cli.role, cli.valid_for);
+    println!("{}", auth::mint(cli.secret.as_bytes(), &claims));
+}
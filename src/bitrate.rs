@@ -0,0 +1,147 @@
+// picks the video encoder bitrate from how the viewers' links are coping.
+// loss-based, the way Google congestion control's loss controller works: back off in proportion
+// to loss above 10%, probe upwards while it stays under 2% and hold in between
+
+const HIGH_LOSS: f64 = 0.10;
+const LOW_LOSS: f64 = 0.02;
+// growth per stats interval while the link is clean
+const INCREASE: f64 = 1.08;
+
+// how a viewer's link did over the last stats interval
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStats {
+    // fraction of the packets sent that were lost, 0.0 to 1.0
+    pub loss: f64,
+    // what reached the viewer, known once transport-wide congestion control feedback flows
+    pub received_kbps: Option<u32>,
+}
+
+impl LinkStats {
+    // the poorer of two links, an encoder shared by several viewers has to suit all of them
+    pub fn worst(self, other: LinkStats) -> LinkStats {
+        LinkStats {
+            loss: self.loss.max(other.loss),
+            received_kbps: match (self.received_kbps, other.received_kbps) {
+                (Some(ours), Some(theirs)) => Some(ours.min(theirs)),
+                (ours, theirs) => ours.or(theirs),
+            },
+        }
+    }
+}
+
+// one per encoder, all in kbit/s
+#[derive(Debug)]
+pub struct BitrateController {
+    min: u32,
+    max: u32,
+    current: u32,
+}
+
+impl BitrateController {
+    pub fn new(min: u32, start: u32, max: u32) -> Self {
+        BitrateController {
+            min,
+            max,
+            current: start.clamp(min, max),
+        }
+    }
+
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    // folds in one stats interval, returning the new bitrate if it changed
+    pub fn update(&mut self, stats: LinkStats) -> Option<u32> {
+        let current = self.current as f64;
+        let target = if stats.loss > HIGH_LOSS {
+            let backed_off = current * (1.0 - 0.5 * stats.loss);
+            // under loss the receive rate is what the path actually carries
+            match stats.received_kbps {
+                Some(received) => backed_off.min(received as f64),
+                None => backed_off,
+            }
+        } else if stats.loss < LOW_LOSS {
+            current * INCREASE
+        } else {
+            current
+        };
+
+        let target = (target.round() as u32).clamp(self.min, self.max);
+        if target == self.current {
+            return None;
+        }
+        self.current = target;
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loss(loss: f64) -> LinkStats {
+        LinkStats {
+            loss,
+            received_kbps: None,
+        }
+    }
+
+    #[test]
+    fn start_is_clamped_to_bounds() {
+        assert_eq!(BitrateController::new(300, 100, 4000).current(), 300);
+        assert_eq!(BitrateController::new(300, 9000, 4000).current(), 4000);
+    }
+
+    #[test]
+    fn clean_link_increases_up_to_max() {
+        let mut controller = BitrateController::new(300, 1000, 1100);
+        assert_eq!(controller.update(loss(0.0)), Some(1080));
+        assert_eq!(controller.update(loss(0.01)), Some(1100));
+        assert_eq!(controller.update(loss(0.0)), None);
+    }
+
+    #[test]
+    fn moderate_loss_holds() {
+        let mut controller = BitrateController::new(300, 1000, 4000);
+        assert_eq!(controller.update(loss(0.05)), None);
+        assert_eq!(controller.current(), 1000);
+    }
+
+    #[test]
+    fn heavy_loss_backs_off_down_to_min() {
+        let mut controller = BitrateController::new(300, 1000, 4000);
+        assert_eq!(controller.update(loss(0.2)), Some(900));
+        assert_eq!(controller.update(loss(1.0)), Some(450));
+        assert_eq!(controller.update(loss(1.0)), Some(300));
+        assert_eq!(controller.update(loss(1.0)), None);
+    }
+
+    #[test]
+    fn heavy_loss_drops_to_received_rate() {
+        let mut controller = BitrateController::new(300, 2000, 4000);
+        let stats = LinkStats {
+            loss: 0.2,
+            received_kbps: Some(700),
+        };
+        assert_eq!(controller.update(stats), Some(700));
+    }
+
+    #[test]
+    fn worst_takes_poorer_of_each() {
+        let a = LinkStats {
+            loss: 0.01,
+            received_kbps: Some(900),
+        };
+        let b = LinkStats {
+            loss: 0.15,
+            received_kbps: None,
+        };
+        assert_eq!(
+            a.worst(b),
+            LinkStats {
+                loss: 0.15,
+                received_kbps: Some(900),
+            }
+        );
+    }
+}
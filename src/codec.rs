@@ -0,0 +1,443 @@
+use std::fmt;
+
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tungstenite::tungstenite;
+
+// newline-delimited JSON framing used on every signaling connection, by both peercomms and the relay
+
+// SDP offers run to a few KB, this leaves plenty of headroom without letting a peer grow our buffer forever
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(std::io::Error),
+    // a line over the limit, everything up to its newline is discarded
+    LineTooLong { max_line_length: usize },
+    // a complete line that isn't a valid message, or a message that can't be serialized
+    Json(serde_json::Error),
+    // the connection closed in the middle of a line
+    Truncated,
+    // the WebSocket handshake or connection failed
+    WebSocket(Box<tungstenite::Error>),
+}
+
+impl CodecError {
+    // bad frames only cost the frame itself, the stream is still usable afterwards
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, CodecError::Io(_) | CodecError::WebSocket(_))
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(err) => write!(f, "i/o error: {}", err),
+            CodecError::LineTooLong { max_line_length } => {
+                write!(f, "line longer than {} bytes", max_line_length)
+            }
+            CodecError::Json(err) => write!(f, "invalid message: {}", err),
+            CodecError::Truncated => write!(f, "connection closed mid-line"),
+            CodecError::WebSocket(err) => write!(f, "websocket error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<std::io::Error> for CodecError {
+    fn from(err: std::io::Error) -> Self {
+        CodecError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(err: serde_json::Error) -> Self {
+        CodecError::Json(err)
+    }
+}
+
+impl From<tungstenite::Error> for CodecError {
+    fn from(err: tungstenite::Error) -> Self {
+        match err {
+            tungstenite::Error::Io(err) => CodecError::Io(err),
+            err => CodecError::WebSocket(Box::new(err)),
+        }
+    }
+}
+
+// the reading half of a signaling connection, whichever transport it runs over
+pub(crate) trait ReadFrames {
+    // next message, Ok(None) once the other side has closed the connection.
+    // safe to use in tokio::select!, a cancelled read loses no data
+    async fn read_frame<T: DeserializeOwned>(&mut self) -> Result<Option<T>, CodecError>;
+}
+
+// the writing half of a signaling connection, whichever transport it runs over
+pub(crate) trait WriteFrames {
+    // writes a line produced by encode
+    async fn write_encoded(&mut self, line: &str) -> Result<(), CodecError>;
+
+    // closes our side of the connection, the other side reads the end of it after everything
+    // written so far
+    async fn shutdown(&mut self) -> Result<(), CodecError>;
+
+    async fn write_frame<T: Serialize>(&mut self, msg: &T) -> Result<(), CodecError> {
+        let line = encode(msg)?;
+        self.write_encoded(&line).await
+    }
+}
+
+// serializes a message as one newline-terminated line
+pub fn encode<T: Serialize>(msg: &T) -> Result<String, CodecError> {
+    Ok(serde_json::to_string(msg)? + "\n")
+}
+
+// splits buffered bytes into lines and parses each one.
+// a bad line is reported once and then skipped, so the next line decodes normally
+#[derive(Debug)]
+pub struct LineDecoder {
+    buffer: Vec<u8>,
+    max_line_length: usize,
+    // set after reporting an over-long line until its newline shows up
+    discarding: bool,
+}
+
+impl LineDecoder {
+    pub fn new(max_line_length: usize) -> Self {
+        LineDecoder {
+            buffer: Vec::new(),
+            max_line_length,
+            discarding: false,
+        }
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    // next frame from the buffer, None until a full line is available
+    pub fn decode<T: DeserializeOwned>(&mut self) -> Option<Result<T, CodecError>> {
+        loop {
+            let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') else {
+                if self.discarding {
+                    self.buffer.clear();
+                } else if self.buffer.len() > self.max_line_length {
+                    self.buffer.clear();
+                    self.discarding = true;
+                    return Some(Err(self.too_long()));
+                }
+                return None;
+            };
+
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+
+            if self.discarding {
+                // tail of a line we already reported
+                self.discarding = false;
+                continue;
+            }
+
+            let line = line.trim_ascii();
+            if line.len() > self.max_line_length {
+                return Some(Err(self.too_long()));
+            }
+            if line.is_empty() {
+                continue;
+            }
+
+            return Some(serde_json::from_slice(line).map_err(CodecError::Json));
+        }
+    }
+
+    // called once the stream has ended, reports a line that never got its newline
+    pub fn finish(&mut self) -> Option<CodecError> {
+        let truncated = !self.discarding && !self.buffer.trim_ascii().is_empty();
+        self.buffer.clear();
+        self.discarding = false;
+
+        truncated.then_some(CodecError::Truncated)
+    }
+
+    fn too_long(&self) -> CodecError {
+        CodecError::LineTooLong {
+            max_line_length: self.max_line_length,
+        }
+    }
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    decoder: LineDecoder,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R, max_line_length: usize) -> Self {
+        FrameReader {
+            reader,
+            decoder: LineDecoder::new(max_line_length),
+            eof: false,
+        }
+    }
+
+    // next message, Ok(None) once the other side has closed the stream.
+    // safe to use in tokio::select!, a cancelled read loses no data
+    pub async fn read_frame<T: DeserializeOwned>(&mut self) -> Result<Option<T>, CodecError> {
+        loop {
+            if let Some(result) = self.decoder.decode() {
+                return result.map(Some);
+            }
+
+            if self.eof {
+                return Ok(None);
+            }
+
+            let mut chunk = [0u8; READ_CHUNK_SIZE];
+            let num_bytes_read = self.reader.read(&mut chunk).await?;
+
+            if num_bytes_read == 0 {
+                self.eof = true;
+                if let Some(err) = self.decoder.finish() {
+                    return Err(err);
+                }
+                return Ok(None);
+            }
+
+            self.decoder.extend(&chunk[..num_bytes_read]);
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> ReadFrames for FrameReader<R> {
+    async fn read_frame<T: DeserializeOwned>(&mut self) -> Result<Option<T>, CodecError> {
+        FrameReader::read_frame(self).await
+    }
+}
+
+pub struct FrameWriter<W> {
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(writer: W) -> Self {
+        FrameWriter { writer }
+    }
+
+    pub async fn write_frame<T: Serialize>(&mut self, msg: &T) -> Result<(), CodecError> {
+        let line = encode(msg)?;
+        self.write_encoded(&line).await
+    }
+
+    // writes a line produced by encode, for callers that queue frames before sending them
+    pub async fn write_encoded(&mut self, line: &str) -> Result<(), CodecError> {
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    // closes our side of the connection, the other side reads EOF after everything written so far
+    pub async fn shutdown(&mut self) -> Result<(), CodecError> {
+        self.writer.shutdown().await?;
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> WriteFrames for FrameWriter<W> {
+    async fn write_encoded(&mut self, line: &str) -> Result<(), CodecError> {
+        FrameWriter::write_encoded(self, line).await
+    }
+
+    async fn shutdown(&mut self) -> Result<(), CodecError> {
+        FrameWriter::shutdown(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientMessage, RelayMessage, Signal};
+
+    fn offer(sdp: &str) -> String {
+        encode(&Signal::Offer(sdp.to_string())).unwrap()
+    }
+
+    fn decode_all(decoder: &mut LineDecoder) -> Vec<Result<Signal, CodecError>> {
+        std::iter::from_fn(|| decoder.decode()).collect()
+    }
+
+    #[test]
+    fn decodes_a_line_fed_one_byte_at_a_time() {
+        let mut decoder = LineDecoder::new(DEFAULT_MAX_LINE_LENGTH);
+        let line = offer("v=0");
+
+        for byte in line.as_bytes() {
+            assert!(decoder.decode::<Signal>().is_none());
+            decoder.extend(&[*byte]);
+        }
+
+        let msg = decoder.decode::<Signal>().unwrap().unwrap();
+        assert!(matches!(msg, Signal::Offer(sdp) if sdp == "v=0"));
+        assert!(decoder.decode::<Signal>().is_none());
+    }
+
+    #[test]
+    fn decodes_several_lines_from_one_chunk() {
+        let mut decoder = LineDecoder::new(DEFAULT_MAX_LINE_LENGTH);
+        decoder.extend((offer("a") + &offer("b") + &offer("c")[..5]).as_bytes());
+
+        let frames = decode_all(&mut decoder);
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| frame.is_ok()));
+
+        decoder.extend(&offer("c").as_bytes()[5..]);
+        assert!(matches!(decoder.decode::<Signal>(), Some(Ok(Signal::Offer(sdp))) if sdp == "c"));
+    }
+
+    #[test]
+    fn resynchronises_after_garbage() {
+        let mut decoder = LineDecoder::new(DEFAULT_MAX_LINE_LENGTH);
+        decoder.extend(b"{not json\n\x00\xff\xfe\n");
+        decoder.extend(offer("after").as_bytes());
+
+        let frames = decode_all(&mut decoder);
+        assert_eq!(frames.len(), 3);
+        assert!(matches!(frames[0], Err(CodecError::Json(_))));
+        assert!(matches!(frames[1], Err(CodecError::Json(_))));
+        assert!(matches!(&frames[2], Ok(Signal::Offer(sdp)) if sdp == "after"));
+    }
+
+    #[test]
+    fn skips_blank_lines_and_accepts_crlf() {
+        let mut decoder = LineDecoder::new(DEFAULT_MAX_LINE_LENGTH);
+        decoder.extend(b"\n\r\n");
+        decoder.extend(offer("crlf").replace('\n', "\r\n").as_bytes());
+
+        let frames = decode_all(&mut decoder);
+        assert_eq!(frames.len(), 1);
+        assert!(matches!(&frames[0], Ok(Signal::Offer(sdp)) if sdp == "crlf"));
+    }
+
+    #[test]
+    fn discards_an_over_long_line_split_across_reads() {
+        let mut decoder = LineDecoder::new(32);
+        let long = offer(&"x".repeat(100));
+
+        decoder.extend(&long.as_bytes()[..50]);
+        assert!(matches!(
+            decoder.decode::<Signal>(),
+            Some(Err(CodecError::LineTooLong {
+                max_line_length: 32
+            }))
+        ));
+
+        // the rest of the long line is dropped without a second error
+        decoder.extend(&long.as_bytes()[50..]);
+        assert!(decoder.decode::<Signal>().is_none());
+
+        decoder.extend(offer("ok").as_bytes());
+        assert!(matches!(decoder.decode::<Signal>(), Some(Ok(Signal::Offer(sdp))) if sdp == "ok"));
+    }
+
+    #[test]
+    fn rejects_an_over_long_line_that_arrives_whole() {
+        let mut decoder = LineDecoder::new(32);
+        decoder.extend((offer(&"x".repeat(100)) + &offer("ok")).as_bytes());
+
+        let frames = decode_all(&mut decoder);
+        assert_eq!(frames.len(), 2);
+        assert!(matches!(frames[0], Err(CodecError::LineTooLong { .. })));
+        assert!(matches!(&frames[1], Ok(Signal::Offer(sdp)) if sdp == "ok"));
+    }
+
+    #[test]
+    fn finish_reports_a_truncated_line() {
+        let mut decoder = LineDecoder::new(DEFAULT_MAX_LINE_LENGTH);
+        decoder.extend(b"{\"Offer\":");
+        assert!(decoder.decode::<Signal>().is_none());
+        assert!(matches!(decoder.finish(), Some(CodecError::Truncated)));
+
+        decoder.extend(b"  \n ");
+        assert!(decoder.decode::<Signal>().is_none());
+        assert!(decoder.finish().is_none());
+    }
+
+    #[test]
+    fn room_events_stay_off_the_wire() {
+        assert!(encode(&Signal::PeerLeft).is_err());
+
+        let mut decoder = LineDecoder::new(DEFAULT_MAX_LINE_LENGTH);
+        decoder.extend(b"{\"Deliver\":{\"from\":2,\"signal\":\"PeerLeft\"}}\n");
+        assert!(matches!(
+            decoder.decode::<RelayMessage>(),
+            Some(Err(CodecError::Json(_)))
+        ));
+    }
+
+    #[test]
+    fn local_signals_stay_off_the_wire() {
+        for signal in [
+            Signal::Reconnected,
+            Signal::StartRecording,
+            Signal::Shutdown,
+        ] {
+            assert!(encode(&signal).is_err());
+        }
+
+        let mut decoder = LineDecoder::new(DEFAULT_MAX_LINE_LENGTH);
+        decoder.extend(b"{\"Send\":{\"to\":null,\"signal\":\"Reconnected\"}}\n");
+        assert!(matches!(
+            decoder.decode::<ClientMessage>(),
+            Some(Err(CodecError::Json(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reader_handles_partial_reads_and_eof() {
+        let (mut client, server) = tokio::io::duplex(8);
+        let mut reader = FrameReader::new(server, DEFAULT_MAX_LINE_LENGTH);
+
+        let writer = tokio::spawn(async move {
+            let bytes = offer("first") + "garbage\n" + &offer("second");
+            for chunk in bytes.as_bytes().chunks(3) {
+                client.write_all(chunk).await.unwrap();
+            }
+        });
+
+        let first = reader.read_frame::<Signal>().await.unwrap();
+        assert!(matches!(first, Some(Signal::Offer(sdp)) if sdp == "first"));
+
+        let garbage = reader.read_frame::<Signal>().await.unwrap_err();
+        assert!(!garbage.is_fatal());
+
+        let second = reader.read_frame::<Signal>().await.unwrap();
+        assert!(matches!(second, Some(Signal::Offer(sdp)) if sdp == "second"));
+
+        writer.await.unwrap();
+        assert!(reader.read_frame::<Signal>().await.unwrap().is_none());
+        assert!(reader.read_frame::<Signal>().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn writer_output_round_trips_through_reader() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut writer = FrameWriter::new(client);
+        let mut reader = FrameReader::new(server, DEFAULT_MAX_LINE_LENGTH);
+
+        let candidate = Signal::IceCandidate {
+            mline_index: 1,
+            candidate: "candidate:1 1 UDP 2122252543 10.0.0.2 50000 typ host".to_string(),
+        };
+        writer.write_frame(&candidate).await.unwrap();
+        drop(writer);
+
+        let msg = reader.read_frame::<Signal>().await.unwrap();
+        assert!(matches!(
+            msg,
+            Some(Signal::IceCandidate { mline_index: 1, candidate }) if candidate.contains("typ host")
+        ));
+        assert!(reader.read_frame::<Signal>().await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use gst::glib::MainLoop;
+use gst::prelude::*;
+use gst::{Element, ElementFactory, Pad, State, glib};
+use gstreamer as gst;
+use gstreamer_webrtc::gst_sdp::SDPMessage;
+
+// why a pipeline failed to start or stopped early
+
+#[derive(Debug)]
+pub enum Error {
+    Init(glib::Error),
+    // no factory by that name, almost always a GStreamer plugin that isn't installed
+    MissingElement { factory: String },
+    // elements or pads that would not link, usually because their caps don't match
+    Link(String),
+    InvalidSdp(String),
+    InvalidConfig(String),
+    // the tokio side of the signaling channel has gone away
+    ChannelClosed,
+    StateChange { element: String, state: State },
+    // any other GStreamer call that failed
+    Gst(glib::BoolError),
+    // a recording could not be started, the live stream is unaffected
+    Recording(String),
+    // an error posted on the pipeline's bus while it was running
+    Pipeline { source: String, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Init(err) => write!(f, "could not initialise GStreamer: {}", err),
+            Error::MissingElement { factory } => write!(
+                f,
+                "GStreamer element {} is not available, is the plugin that provides it installed?",
+                factory
+            ),
+            Error::Link(what) => write!(f, "could not link {}", what),
+            Error::InvalidSdp(reason) => write!(f, "invalid SDP: {}", reason),
+            Error::InvalidConfig(reason) => write!(f, "invalid configuration: {}", reason),
+            Error::ChannelClosed => write!(f, "signaling channel closed"),
+            Error::StateChange { element, state } => {
+                write!(f, "{} could not change state to {:?}", element, state)
+            }
+            Error::Gst(err) => write!(f, "GStreamer error: {}", err),
+            Error::Recording(reason) => write!(f, "could not record: {}", reason),
+            Error::Pipeline { source, message } => write!(f, "{}: {}", source, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<glib::BoolError> for Error {
+    fn from(err: glib::BoolError) -> Self {
+        Error::Gst(err)
+    }
+}
+
+// the GStreamer calls the pipelines make most, returning Error instead of their own error types
+
+pub(crate) fn make_element(factory: &str) -> Result<Element, Error> {
+    ElementFactory::make(factory)
+        .build()
+        .map_err(|_| Error::MissingElement {
+            factory: factory.to_string(),
+        })
+}
+
+pub(crate) fn link_many(elements: &[&Element]) -> Result<(), Error> {
+    Element::link_many(elements).map_err(|err| Error::Link(err.to_string()))
+}
+
+pub(crate) fn link_pads(src: &Pad, sink: &Pad) -> Result<(), Error> {
+    src.link(sink).map(|_| ()).map_err(|err| {
+        Error::Link(format!(
+            "{} to {}: {:?}",
+            pad_name(src),
+            pad_name(sink),
+            err
+        ))
+    })
+}
+
+pub(crate) fn set_state(element: &impl IsA<Element>, state: State) -> Result<(), Error> {
+    element
+        .set_state(state)
+        .map(|_| ())
+        .map_err(|_| Error::StateChange {
+            element: element.upcast_ref::<Element>().name().to_string(),
+            state,
+        })
+}
+
+pub(crate) fn parse_sdp(sdp: &str) -> Result<SDPMessage, Error> {
+    SDPMessage::parse_buffer(sdp.as_bytes()).map_err(|err| Error::InvalidSdp(err.to_string()))
+}
+
+// element:pad, the way GStreamer's own logs name pads
+fn pad_name(pad: &Pad) -> String {
+    match pad.parent_element() {
+        Some(element) => format!("{}:{}", element.name(), pad.name()),
+        None => pad.name().to_string(),
+    }
+}
+
+// the first error that stopped a pipeline, shared by the callbacks that can stop its main loop
+#[derive(Clone, Default)]
+pub(crate) struct Failure(Arc<Mutex<Option<Error>>>);
+
+impl Failure {
+    pub(crate) fn stop(&self, main_loop: &MainLoop, err: Error) {
+        eprintln!("stopping pipeline: {}", err);
+        self.0.lock().unwrap().get_or_insert(err);
+        main_loop.quit();
+    }
+
+    pub(crate) fn take(&self) -> Option<Error> {
+        self.0.lock().unwrap().take()
+    }
+}
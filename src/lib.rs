@@ -1,15 +1,120 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
+use crate::config::VideoCodec;
+
+pub mod auth;
+pub mod bitrate;
+pub mod codec;
+pub mod config;
+pub mod error;
+pub mod mediacodecs;
 pub mod mediaconsumer;
 pub mod mediaproducer;
+mod mediarecorder;
+mod mediashutdown;
+mod mediasimulcast;
+mod mediastats;
 pub mod peercomms;
+pub mod relay;
+pub mod signaling;
+pub mod tls;
+pub mod unixsignals;
+pub mod webrtc;
+pub mod websocket;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Signal {
     Offer(String),
     Answer(String),
-    IceCandidate { mline_index: u32, candidate: String },
+    IceCandidate {
+        mline_index: u32,
+        candidate: String,
+    },
+    // sent by a consumer to each peer that joins, the producer offers it one of these
+    Capabilities {
+        video_codecs: Vec<VideoCodec>,
+    },
+    // generated locally from the relay's room notifications. skipped by serde, so a peer can't
+    // send them and a pipeline can't pass them on
+    #[serde(skip)]
+    PeerJoined,
+    #[serde(skip)]
+    PeerLeft,
+    // generated locally once a dropped relay connection has been re-established
+    #[serde(skip)]
+    Reconnected,
+    // generated locally by the binaries on SIGUSR1/SIGUSR2
+    #[serde(skip)]
+    StartRecording,
+    #[serde(skip)]
+    StopRecording,
+    // generated locally on Ctrl-C or SIGTERM, the pipeline says Bye and drains before stopping
+    #[serde(skip)]
+    Shutdown,
+    // sent to the whole room by a peer that is going away
+    Bye,
+    // sent to one peer to end the session with it, the sender stays in the room
+    Leave,
+}
+
+// id the relay assigns to each connection, stable for the lifetime of that connection
+pub type PeerId = u64;
+
+// a Signal together with the remote peer it is addressed to or was received from.
+// outgoing envelopes without a peer are delivered to everyone else in the room
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub peer: Option<PeerId>,
+    pub signal: Signal,
+}
+
+// messages a client sends to the relay
+#[derive(Serialize, Deserialize)]
+pub enum ClientMessage {
+    // a relay with an auth secret wants a token for the room, see auth
+    Join {
+        room: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    Send {
+        to: Option<PeerId>,
+        signal: Signal,
+    },
+}
+
+// by hand so a logged Join doesn't give its token away
+impl fmt::Debug for ClientMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientMessage::Join { room, token } => f
+                .debug_struct("Join")
+                .field("room", room)
+                .field("token", &token.as_ref().map(|_| "<redacted>"))
+                .finish(),
+            ClientMessage::Send { to, signal } => f
+                .debug_struct("Send")
+                .field("to", to)
+                .field("signal", signal)
+                .finish(),
+        }
+    }
+}
+
+// messages the relay sends to a client
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RelayMessage {
+    Welcome { peer_id: PeerId },
+    Deliver { from: PeerId, signal: Signal },
+    PeerJoined { peer_id: PeerId },
+    PeerLeft { peer_id: PeerId },
+    // the relay is stopping, it closes the connection once the client has
+    ShuttingDown,
+    // the relay refused the client's token or a message it sent, and closes the connection
+    Rejected { reason: String },
 }
 
-pub const HOST: &str = "0.0.0.0";
-// pub const HOST: &str = "165.227.10.141";
+// room the binaries join when none is given
+pub const DEFAULT_ROOM: &str = "default";
@@ -0,0 +1,310 @@
+use gst::prelude::*;
+use gst::{Element, ElementFactory, ElementFactoryType, Rank};
+use gstreamer as gst;
+use gstreamer_webrtc::gst_sdp::{SDPMediaRef, SDPMessageRef};
+
+use crate::config::VideoCodec;
+use crate::error::{Error, make_element};
+
+// the GStreamer side of each VideoCodec: which elements encode, payload and depayload it,
+// and the settings that keep encoding latency down
+
+// software encoder with low-latency settings equivalent to x264's ultrafast/zerolatency.
+// every codec gets a keyframe at least every 15 frames so viewers joining mid-stream start quickly
+pub(crate) fn make_video_encoder(codec: VideoCodec) -> Result<Element, Error> {
+    let encoder = match codec {
+        VideoCodec::H264 => {
+            let encoder = make_element("x264enc")?;
+            encoder.set_property_from_str("speed-preset", "ultrafast");
+            encoder.set_property_from_str("tune", "zerolatency");
+            encoder.set_property("intra-refresh", true);
+            encoder.set_property_from_str("key-int-max", "15");
+            encoder
+        }
+        VideoCodec::H265 => {
+            let encoder = make_element("x265enc")?;
+            encoder.set_property_from_str("speed-preset", "ultrafast");
+            encoder.set_property_from_str("tune", "zerolatency");
+            encoder.set_property_from_str("key-int-max", "15");
+            encoder
+        }
+        VideoCodec::Vp8 | VideoCodec::Vp9 => {
+            let encoder = make_element(match codec {
+                VideoCodec::Vp8 => "vp8enc",
+                _ => "vp9enc",
+            })?;
+            // deadline 1 is libvpx's realtime mode, cpu-used trades quality for speed
+            encoder.set_property("deadline", 1i64);
+            encoder.set_property_from_str("cpu-used", "8");
+            encoder.set_property_from_str("end-usage", "cbr");
+            encoder.set_property_from_str("lag-in-frames", "0");
+            encoder.set_property_from_str("keyframe-max-dist", "15");
+            encoder
+        }
+        VideoCodec::Av1 => {
+            let encoder = make_element("av1enc")?;
+            encoder.set_property_from_str("usage-profile", "realtime");
+            encoder.set_property_from_str("cpu-used", "8");
+            encoder.set_property_from_str("end-usage", "cbr");
+            encoder.set_property_from_str("lag-in-frames", "0");
+            encoder.set_property_from_str("keyframe-max-dist", "15");
+            encoder
+        }
+    };
+
+    Ok(encoder)
+}
+
+// the encoders' rate control targets, in kbit/s. each one takes its bitrate in its own units
+pub(crate) fn set_video_bitrate(encoder: &Element, codec: VideoCodec, kbps: u32) {
+    match codec {
+        VideoCodec::H264 | VideoCodec::H265 => encoder.set_property("bitrate", kbps),
+        // libvpx wants bit/s
+        VideoCodec::Vp8 | VideoCodec::Vp9 => encoder.set_property(
+            "target-bitrate",
+            i32::try_from(kbps.saturating_mul(1000)).unwrap_or(i32::MAX),
+        ),
+        VideoCodec::Av1 => encoder.set_property("target-bitrate", kbps),
+    }
+}
+
+pub(crate) fn make_video_payloader(codec: VideoCodec) -> Result<Element, Error> {
+    let payloader = match codec {
+        VideoCodec::H264 | VideoCodec::H265 => {
+            let payloader = make_element(match codec {
+                VideoCodec::H264 => "rtph264pay",
+                _ => "rtph265pay",
+            })?;
+            // viewers join mid-stream, so repeat the parameter sets with every keyframe
+            payloader.set_property("config-interval", -1i32);
+            payloader
+        }
+        VideoCodec::Vp8 | VideoCodec::Vp9 => {
+            let payloader = make_element(match codec {
+                VideoCodec::Vp8 => "rtpvp8pay",
+                _ => "rtpvp9pay",
+            })?;
+            // browsers expect picture ids on VP8/VP9 streams
+            payloader.set_property_from_str("picture-id-mode", "15-bit");
+            payloader
+        }
+        VideoCodec::Av1 => make_element("rtpav1pay")?,
+    };
+    payloader.set_property("pt", payload_type(codec));
+
+    Ok(payloader)
+}
+
+// the transport-wide congestion control header extension, so viewers report what arrived when
+const TWCC_URI: &str = "http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01";
+const TWCC_EXTENSION_ID: u32 = 1;
+
+// goes right after a video payloader. the payloader adds the header extensions its downstream
+// caps ask for, and the same extmap ends up in the offer through video_rtp_caps
+pub(crate) fn make_twcc_filter() -> Result<Element, Error> {
+    let filter = make_element("capsfilter")?;
+    let caps = gst::Caps::builder("application/x-rtp")
+        .field(format!("extmap-{}", TWCC_EXTENSION_ID), TWCC_URI)
+        .build();
+    filter.set_property("caps", &caps);
+    Ok(filter)
+}
+
+// fixed dynamic payload types, so one offer can carry every codec without clashes
+fn payload_type(codec: VideoCodec) -> u32 {
+    match codec {
+        VideoCodec::H264 => 96,
+        VideoCodec::Vp8 => 97,
+        VideoCodec::Vp9 => 98,
+        VideoCodec::H265 => 99,
+        VideoCodec::Av1 => 100,
+    }
+}
+
+// the name used in SDP rtpmap lines and RTP caps
+fn encoding_name(codec: VideoCodec) -> &'static str {
+    match codec {
+        VideoCodec::H264 => "H264",
+        VideoCodec::Vp8 => "VP8",
+        VideoCodec::Vp9 => "VP9",
+        VideoCodec::H265 => "H265",
+        VideoCodec::Av1 => "AV1",
+    }
+}
+
+pub(crate) fn codec_for_encoding_name(name: &str) -> Option<VideoCodec> {
+    VideoCodec::ALL
+        .into_iter()
+        .find(|codec| encoding_name(*codec).eq_ignore_ascii_case(name))
+}
+
+// RTP caps for a transceiver's codec-preferences, one structure per codec in preference order.
+// for an offer they carry our payload types and TWCC extension, for an answer they leave both
+// out to match whatever the offer picked
+pub(crate) fn video_rtp_caps(codecs: &[VideoCodec], for_offer: bool) -> gst::Caps {
+    let mut caps = gst::Caps::new_empty();
+    let caps_mut = caps.get_mut().expect("new caps are writable");
+    for &codec in codecs {
+        let mut structure = gst::Structure::builder("application/x-rtp")
+            .field("media", "video")
+            .field("encoding-name", encoding_name(codec))
+            .field("clock-rate", 90000i32)
+            .build();
+        if for_offer {
+            structure.set("payload", payload_type(codec) as i32);
+            structure.set(format!("extmap-{}", TWCC_EXTENSION_ID), TWCC_URI);
+        }
+        caps_mut.append_structure(structure);
+    }
+    caps
+}
+
+pub(crate) fn depayloader_name(codec: VideoCodec) -> &'static str {
+    match codec {
+        VideoCodec::H264 => "rtph264depay",
+        VideoCodec::Vp8 => "rtpvp8depay",
+        VideoCodec::Vp9 => "rtpvp9depay",
+        VideoCodec::H265 => "rtph265depay",
+        VideoCodec::Av1 => "rtpav1depay",
+    }
+}
+
+// parses the depayloaded stream into complete frames for a muxer, libvpx's VP8 output already is
+pub(crate) fn parser_name(codec: VideoCodec) -> Option<&'static str> {
+    match codec {
+        VideoCodec::H264 => Some("h264parse"),
+        VideoCodec::Vp8 => None,
+        VideoCodec::Vp9 => Some("vp9parse"),
+        VideoCodec::H265 => Some("h265parse"),
+        VideoCodec::Av1 => Some("av1parse"),
+    }
+}
+
+fn caps_name(codec: VideoCodec) -> &'static str {
+    match codec {
+        VideoCodec::H264 => "video/x-h264",
+        VideoCodec::Vp8 => "video/x-vp8",
+        VideoCodec::Vp9 => "video/x-vp9",
+        VideoCodec::H265 => "video/x-h265",
+        VideoCodec::Av1 => "video/x-av1",
+    }
+}
+
+// codecs decodebin will manage here, the ones with both a depayloader and a decoder installed.
+// gst::init must have been called
+pub fn decodable_video_codecs() -> Vec<VideoCodec> {
+    let decoders = ElementFactory::factories_with_type(
+        ElementFactoryType::DECODER | ElementFactoryType::MEDIA_VIDEO,
+        Rank::MARGINAL,
+    );
+
+    VideoCodec::ALL
+        .into_iter()
+        .filter(|codec| {
+            let caps = gst::Caps::new_empty_simple(caps_name(*codec));
+            ElementFactory::find(depayloader_name(*codec)).is_some()
+                && decoders
+                    .iter()
+                    .any(|decoder| decoder.can_sink_any_caps(&caps))
+        })
+        .collect()
+}
+
+// (payload type, codec) for each format of an m-line that is one of our video codecs, in m-line order
+fn video_formats(media: &SDPMediaRef) -> Vec<(String, VideoCodec)> {
+    // rtpmap lines look like "96 VP8/90000"
+    let rtpmaps: Vec<(&str, VideoCodec)> = (0..media.attributes_len())
+        .filter_map(|idx| media.attribute(idx))
+        .filter(|attribute| attribute.key() == "rtpmap")
+        .filter_map(|attribute| {
+            let (payload_type, encoding) = attribute.value()?.split_once(' ')?;
+            let name = encoding.split('/').next()?;
+            Some((payload_type, codec_for_encoding_name(name)?))
+        })
+        .collect();
+
+    (0..media.formats_len())
+        .filter_map(|idx| media.format(idx))
+        .filter_map(|format| {
+            let (_, codec) = rtpmaps.iter().find(|(pt, _)| *pt == format)?;
+            Some((format.to_string(), *codec))
+        })
+        .collect()
+}
+
+// the codec an answer settled on, the first one listed on its video m-line
+pub(crate) fn negotiated_video_codec(sdp: &SDPMessageRef) -> Option<VideoCodec> {
+    (0..sdp.medias_len())
+        .filter_map(|idx| sdp.media(idx))
+        .filter(|media| media.media() == Some("video"))
+        .find_map(|media| video_formats(media).first().map(|(_, codec)| *codec))
+}
+
+// SDP munging for the answer: drops the video codecs we can't decode and lists the rest in our
+// preference order, so the first format is the one the producer should send.
+// formats that aren't video codecs (rtx, red, ulpfec) stay after them untouched.
+// returns the codec now listed first, None if the offer had none we can decode
+pub(crate) fn prefer_video_codecs(
+    sdp: &mut SDPMessageRef,
+    preference: &[VideoCodec],
+) -> Result<Option<VideoCodec>, Error> {
+    let mut chosen = None;
+
+    for media_idx in 0..sdp.medias_len() {
+        let Some(media) = sdp.media_mut(media_idx) else {
+            continue;
+        };
+        if media.media() != Some("video") {
+            continue;
+        }
+
+        let codec_formats = video_formats(media);
+        let mut kept: Vec<&(String, VideoCodec)> = codec_formats
+            .iter()
+            .filter(|(_, codec)| preference.contains(codec))
+            .collect();
+        kept.sort_by_key(|(_, codec)| preference.iter().position(|preferred| preferred == codec));
+        let dropped: Vec<&str> = codec_formats
+            .iter()
+            .filter(|format| !kept.contains(format))
+            .map(|(payload_type, _)| payload_type.as_str())
+            .collect();
+        let others: Vec<String> = (0..media.formats_len())
+            .filter_map(|idx| media.format(idx))
+            .filter(|format| !codec_formats.iter().any(|(pt, _)| pt == format))
+            .map(str::to_string)
+            .collect();
+
+        while media.formats_len() > 0 {
+            media.remove_format(0).map_err(sdp_error)?;
+        }
+        for (payload_type, _) in &kept {
+            media.add_format(payload_type).map_err(sdp_error)?;
+        }
+        for format in &others {
+            media.add_format(format).map_err(sdp_error)?;
+        }
+
+        // rtpmap, fmtp and rtcp-fb lines of dropped payload types start with the payload type
+        for idx in (0..media.attributes_len()).rev() {
+            let Some(attribute) = media.attribute(idx) else {
+                continue;
+            };
+            let per_format = matches!(attribute.key(), "rtpmap" | "fmtp" | "rtcp-fb");
+            let payload_type = attribute.value().and_then(|value| value.split(' ').next());
+            if per_format && payload_type.is_some_and(|pt| dropped.contains(&pt)) {
+                media.remove_attribute(idx).map_err(sdp_error)?;
+            }
+        }
+
+        if chosen.is_none() {
+            chosen = kept.first().map(|(_, codec)| *codec);
+        }
+    }
+
+    Ok(chosen)
+}
+
+pub(crate) fn sdp_error(err: gst::glib::BoolError) -> Error {
+    Error::InvalidSdp(err.to_string())
+}
@@ -1,140 +1,212 @@
-use gst::glib::MainLoop;
+use std::sync::{Arc, Mutex};
+
+use gst::glib::{MainContext, MainLoop};
 use gst::prelude::*;
-use gst::{Element, ElementFactory, MessageView, Pipeline, State};
-use gstreamer::glib::{ControlFlow, source};
+use gst::{Element, MessageView, Pad, Pipeline, State};
 use gstreamer::{self as gst, PadDirection, Promise};
-use gstreamer_webrtc::WebRTCSessionDescription;
-use gstreamer_webrtc::gst_sdp::SDPMessage;
+use gstreamer_app::{AppSink, AppSinkCallbacks};
+use gstreamer_webrtc::{
+    WebRTCICEConnectionState, WebRTCPeerConnectionState, WebRTCRTPTransceiver,
+    WebRTCSessionDescription,
+};
 use tokio::sync::mpsc::{Receiver, Sender};
 
-use crate::{HOST, Signal};
-
-pub fn run_consumer_pipeline(send_to_tokio: Sender<Signal>, mut gst_recv: Receiver<Signal>) {
-    gst::init().unwrap();
-
-    let pipeline = Pipeline::with_name("pipeline");
-
-    let webrtc_bin = ElementFactory::make("webrtcbin")
-        .property_from_str("stun-server", format!("stun://{}:3478", HOST).as_str())
-        .property_from_str(
-            "turn-server",
-            format!("turn://test:test@{}:3478", HOST).as_str(),
-        )
-        .build()
-        .unwrap();
-    let audio_converter = ElementFactory::make("audioconvert").build().unwrap();
-    let video_converter = ElementFactory::make("videoconvert").build().unwrap();
-    let audio_resampler = ElementFactory::make("audioresample").build().unwrap();
-    let video_scaler = ElementFactory::make("videoscale").build().unwrap();
-    let audio_sink = ElementFactory::make("autoaudiosink").build().unwrap();
-    let video_sink = ElementFactory::make("autovideosink").build().unwrap();
-
-    pipeline
-        .add_many([
-            &webrtc_bin,
-            &audio_converter,
-            &video_converter,
-            &audio_resampler,
-            &video_scaler,
-            &audio_sink,
-            &video_sink,
-        ])
-        .unwrap();
-
-    Element::link_many([&audio_converter, &audio_resampler, &audio_sink]).unwrap();
-    Element::link_many([&video_converter, &video_scaler, &video_sink]).unwrap();
-
-    webrtc_bin.connect_notify(None, |x, y| {
-        println!("notify called");
-        println!("{:?}", y.name());
-        let sig = x.property::<gstreamer_webrtc::WebRTCSignalingState>("signaling-state");
-        let ice = x.property::<gstreamer_webrtc::WebRTCICEGatheringState>("ice-gathering-state");
-        println!("signaling-state: {:?}", sig);
-        println!("ice-gathering-state: {:?}", ice);
-    });
+use crate::config::{Config, IceConfig};
+use crate::error::{Error, Failure, link_many, link_pads, make_element, parse_sdp, set_state};
+use crate::mediacodecs::{decodable_video_codecs, prefer_video_codecs, video_rtp_caps};
+use crate::mediarecorder::{Recorder, recorded_media};
+use crate::mediashutdown::{begin_shutdown, say_bye};
+use crate::mediasimulcast::{offered_layers, receive_only_layer};
+use crate::webrtc::configure_ice;
+use crate::{Envelope, PeerId, Signal};
+
+// where decoded media ends up
+pub enum ConsumerOutput {
+    // autoaudiosink and autovideosink
+    Playback,
+    // appsinks handing every decoded frame to the callbacks, no display or sound server needed
+    Headless(FrameCallbacks),
+    // nothing is decoded, the streams are only recorded, from the moment both arrive
+    RecordOnly,
+}
 
-    // forgot this in prev part
-    let sender_clone = send_to_tokio.clone();
-    webrtc_bin.connect("on-ice-candidate", false, move |values| {
-        println!("on ice candidate event, sending to peer.");
+// callbacks run on GStreamer streaming threads, slow ones hold up the stream
+pub struct FrameCallbacks {
+    pub on_video_frame: Box<dyn FnMut(VideoFrame) + Send>,
+    pub on_audio_samples: Box<dyn FnMut(AudioSamples) + Send>,
+    // every change of webrtcbin's ice-connection-state, Connected once media can flow
+    pub on_ice_connection_state: Box<dyn FnMut(WebRTCICEConnectionState) + Send>,
+}
 
-        let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
-        let mline_index = values[1].get::<u32>().expect("Invalid argument");
-        let candidate = values[2].get::<String>().expect("Invalid argument");
+// a decoded frame, always packed RGB. read the pixels with buffer.map_readable()
+pub struct VideoFrame {
+    pub caps: gst::Caps,
+    pub pts: Option<gst::ClockTime>,
+    pub buffer: gst::Buffer,
+}
 
-        println!("mline_index: {}", mline_index);
-        println!("candidate: {}", candidate);
+impl VideoFrame {
+    pub fn width(&self) -> Option<i32> {
+        self.caps.structure(0)?.get("width").ok()
+    }
 
-        sender_clone
-            .blocking_send(Signal::IceCandidate {
-                mline_index,
-                candidate,
-            })
-            .unwrap();
+    pub fn height(&self) -> Option<i32> {
+        self.caps.structure(0)?.get("height").ok()
+    }
+}
 
-        None
-    });
+// a chunk of decoded audio, always interleaved 32-bit float samples
+pub struct AudioSamples {
+    pub caps: gst::Caps,
+    pub pts: Option<gst::ClockTime>,
+    pub buffer: gst::Buffer,
+}
 
-    let pipeline_clone = pipeline.clone();
-    webrtc_bin.connect_pad_added(move |_, pad| {
-        println!("Pad added to webrtc_bin: {}", pad.name());
-        let audio_converter_clone = audio_converter.clone();
-        let video_converter_clone = video_converter.clone();
-        if pad.direction() == PadDirection::Src {
-            let decode_bin = ElementFactory::make("decodebin").build().unwrap();
-            decode_bin.connect_pad_added(move |_src, src_pad| {
-                println!("pad added to decodebin");
-                let new_pad_caps = src_pad.current_caps().unwrap();
-                let new_pad_struct = new_pad_caps.structure(0).unwrap();
-                let new_pad_type = new_pad_struct.name();
-
-                let is_video = new_pad_type.starts_with("video/x-raw");
-                let is_audio = new_pad_type.starts_with("audio/x-raw");
-
-                let el: Option<&Element> = if is_audio {
-                    Some(&audio_converter_clone)
-                } else if is_video {
-                    Some(&video_converter_clone)
-                } else {
-                    None
-                };
+impl AudioSamples {
+    pub fn rate(&self) -> Option<i32> {
+        self.caps.structure(0)?.get("rate").ok()
+    }
 
-                if let Some(el) = el {
-                    let converter_sink_pad = el.static_pad("sink").unwrap();
+    pub fn channels(&self) -> Option<i32> {
+        self.caps.structure(0)?.get("channels").ok()
+    }
+}
 
-                    if !converter_sink_pad.is_linked() {
-                        let res = src_pad.link(&converter_sink_pad);
+// Runs until the pipeline errors, the signaling channel closes or a Shutdown signal has drained it
+pub fn run_consumer_pipeline(
+    config: &Config,
+    output: ConsumerOutput,
+    send_to_tokio: Sender<Envelope>,
+    gst_recv: Receiver<Envelope>,
+) -> Result<(), Error> {
+    gst::init().map_err(Error::Init)?;
+
+    // each pipeline drives its own main context, so a producer and a consumer can share a process
+    let context = MainContext::new();
+    context.with_thread_default(|| {
+        consumer_pipeline(&context, config, output, send_to_tokio, gst_recv)
+    })?
+}
 
-                        if res.is_err() {
-                            eprintln!("Error linking pad");
-                        } else {
-                            println!("Converter sink pad linked");
-                        }
-                    }
-                }
-            });
+fn consumer_pipeline(
+    context: &MainContext,
+    config: &Config,
+    output: ConsumerOutput,
+    send_to_tokio: Sender<Envelope>,
+    mut gst_recv: Receiver<Envelope>,
+) -> Result<(), Error> {
+    config.consumer.validate().map_err(Error::InvalidConfig)?;
+    let pipeline = Pipeline::with_name("pipeline");
 
-            pipeline_clone.add(&decode_bin).unwrap();
-            decode_bin.sync_state_with_parent().unwrap();
-            let sink_pad = decode_bin.static_pad("sink").unwrap();
-            pad.link(&sink_pad).unwrap();
+    let mut on_ice_connection_state = None;
+    let sinks = match output {
+        ConsumerOutput::Playback => Some((
+            make_element("autoaudiosink")?,
+            make_element("autovideosink")?,
+        )),
+        ConsumerOutput::Headless(FrameCallbacks {
+            on_video_frame,
+            on_audio_samples,
+            on_ice_connection_state: on_state,
+        }) => {
+            on_ice_connection_state = Some(Arc::new(Mutex::new(on_state)));
+            Some(make_app_sinks(on_video_frame, on_audio_samples)?)
         }
+        ConsumerOutput::RecordOnly => None,
+    };
+    // with sinks to play to, record from the start only if asked to
+    let record_from_start = sinks.is_none() || config.recording.enabled;
+
+    // the converters decoded streams are linked to, None when nothing is decoded
+    let converters = match sinks {
+        Some((audio_sink, video_sink)) => {
+            let audio_converter = make_element("audioconvert")?;
+            let video_converter = make_element("videoconvert")?;
+            let audio_resampler = make_element("audioresample")?;
+            let video_scaler = make_element("videoscale")?;
+
+            pipeline.add_many([
+                &audio_converter,
+                &video_converter,
+                &audio_resampler,
+                &video_scaler,
+                &audio_sink,
+                &video_sink,
+            ])?;
+
+            link_many(&[&audio_converter, &audio_resampler, &audio_sink])?;
+            link_many(&[&video_converter, &video_scaler, &video_sink])?;
+            Some((audio_converter, video_converter))
+        }
+        None => None,
+    };
+
+    // records the streams as they arrived, before they are decoded
+    let recorder = Arc::new(Mutex::new(Recorder::new(&config.recording, "consumer")));
+
+    // what we can decode, the configured codecs first in their configured order
+    let mut video_codecs = decodable_video_codecs();
+    video_codecs.sort_by_key(|codec| {
+        config
+            .video
+            .codecs
+            .iter()
+            .position(|preferred| preferred == codec)
+            .unwrap_or(usize::MAX)
     });
+    println!("can decode {:?} video, most preferred first.", video_codecs);
+    if video_codecs.is_empty() {
+        eprintln!("no video decoders found, producers will have nothing to offer us.");
+    }
+
+    let mut setup = SessionSetup {
+        pipeline: pipeline.clone(),
+        ice: config.ice.clone(),
+        converters,
+        recorder: recorder.clone(),
+        record_from_start,
+        on_ice_connection_state,
+        send_to_tokio: send_to_tokio.clone(),
+        started: 0,
+    };
+    let mut session = setup.start()?;
+
+    let main_loop = MainLoop::new(Some(context), false);
+    let failure = Failure::default();
 
-    let webrtc_bin_clone = webrtc_bin.clone();
     let sender_clone = send_to_tokio.clone();
-    source::idle_add(move || {
-        let msg_result = gst_recv.try_recv();
+    let main_loop_clone = main_loop.clone();
+    let failure_clone = failure.clone();
+    let simulcast_layer = config.consumer.simulcast_layer.clone();
+    let pipeline_clone = pipeline.clone();
+    let recorder_clone = recorder.clone();
+    let context_clone = context.clone();
+    let mut shutting_down = false;
+    // woken by each signal from tokio, the main context sleeps in between
+    let signal_task = context.spawn_local(async move {
+        loop {
+            let Some(Envelope { peer, signal }) = gst_recv.recv().await else {
+                failure_clone.stop(&main_loop_clone, Error::ChannelClosed);
+                break;
+            };
+
+            // nothing new is negotiated while the pipeline drains
+            if shutting_down {
+                println!("shutting down, ignoring {:?} from {:?}.", signal, peer);
+                continue;
+            }
 
-        if let Ok(signal) = msg_result {
-            let webrtc_bin_clone = webrtc_bin_clone.clone();
-            let webrtc_bin_clone2 = webrtc_bin_clone.clone();
+            let webrtc_bin_clone = session.webrtc_bin.clone();
             let sender_clone = sender_clone.clone();
             match signal {
                 Signal::IceCandidate {
                     mline_index,
                     candidate,
                 } => {
+                    if peer != session.producer() {
+                        println!("ignoring ICE candidate from {:?}, not our producer.", peer);
+                        continue;
+                    }
                     println!(
                         "Ice candidate received. mline_index: {}, candidate: {}. setting on webrtcbin.",
                         mline_index, candidate
@@ -146,72 +218,684 @@ pub fn run_consumer_pipeline(send_to_tokio: Sender<Signal>, mut gst_recv: Receiv
                 Signal::Answer(_sdp) => {
                     println!("should not get answer in consumer.");
                 }
-                Signal::Offer(sdp) => {
-                    let promise = Promise::with_change_func(move |res| {
-                        let option = res.unwrap();
-
-                        if let Some(val) = option {
-                            let answer = val
-                                .get::<gstreamer_webrtc::WebRTCSessionDescription>("answer")
-                                .unwrap();
+                Signal::PeerJoined => {
+                    // any peer could be the producer, tell each one what we can decode so its offer
+                    // fits
+                    println!("peer {:?} joined the room. sending our capabilities.", peer);
+                    let envelope = Envelope {
+                        peer,
+                        signal: Signal::Capabilities {
+                            video_codecs: video_codecs.clone(),
+                        },
+                    };
+                    if sender_clone.send(envelope).await.is_err() {
+                        failure_clone.stop(&main_loop_clone, Error::ChannelClosed);
+                        break;
+                    }
+                }
+                Signal::Capabilities { .. } => {
+                    println!("ignoring capabilities from another consumer {:?}.", peer);
+                }
+                ended @ (Signal::PeerLeft | Signal::Bye | Signal::Leave)
+                    if peer.is_some() && peer == session.producer() =>
+                {
+                    println!(
+                        "producer {:?} ended the session ({:?}). waiting for a new offer.",
+                        peer, ended
+                    );
+                    if let Err(err) = setup.restart(&mut session) {
+                        failure_clone.stop(&main_loop_clone, err);
+                        break;
+                    }
 
+                    // producers we turned down have dropped their branch for us, ask them all again
+                    let envelope = Envelope {
+                        peer: None,
+                        signal: Signal::Capabilities {
+                            video_codecs: video_codecs.clone(),
+                        },
+                    };
+                    if sender_clone.send(envelope).await.is_err() {
+                        failure_clone.stop(&main_loop_clone, Error::ChannelClosed);
+                        break;
+                    }
+                }
+                Signal::PeerLeft => {
+                    println!("peer {:?} left the room.", peer);
+                }
+                Signal::Bye => {
+                    println!("peer {:?} said bye.", peer);
+                }
+                Signal::Leave => {
+                    println!("ignoring leave from {:?}, not our producer.", peer);
+                }
+                Signal::Reconnected if peer.is_none() => {
+                    // the producer drops its branches too, and the relay announces it again
+                    println!("signaling reconnected. ending the session, waiting for a new offer.");
+                    if let Err(err) = setup.restart(&mut session) {
+                        failure_clone.stop(&main_loop_clone, err);
+                        break;
+                    }
+                }
+                Signal::StartRecording if peer.is_none() => {
+                    // a recording that won't start leaves playback as it was
+                    if let Err(err) = recorder_clone.lock().unwrap().start(&pipeline_clone) {
+                        eprintln!("{}", err);
+                    }
+                }
+                Signal::StopRecording if peer.is_none() => {
+                    recorder_clone.lock().unwrap().stop();
+                }
+                Signal::Shutdown if peer.is_none() => {
+                    shutting_down = true;
+                    // viewers may only say bye to their producer, the relay drops it otherwise
+                    if let Some(producer) = session.producer() {
+                        say_bye(&sender_clone, Some(producer)).await;
+                    }
+                    begin_shutdown(&pipeline_clone, &main_loop_clone, &context_clone);
+                }
+                // a custom signaling transport could pass them on, only the local side may send them
+                Signal::Reconnected
+                | Signal::StartRecording
+                | Signal::StopRecording
+                | Signal::Shutdown => {
+                    println!("ignoring local signal from {:?}.", peer);
+                }
+                Signal::Offer(sdp) => {
+                    // a bad offer leaves us waiting for the next one
+                    let sdp = match parse_sdp(&sdp) {
+                        Ok(sdp) => sdp,
+                        Err(err) => {
+                            eprintln!("ignoring offer from {:?}: {}", peer, err);
+                            continue;
+                        }
+                    };
+
+                    // webrtcbin keeps the session id in the origin line for renegotiations and
+                    // picks a new one for every new session
+                    let session_id = sdp
+                        .origin()
+                        .and_then(|origin| origin.sess_id())
+                        .map(str::to_string);
+                    match session.producer() {
+                        // one producer at a time, while ours is connected the others are told to
+                        // drop their branch for us
+                        Some(current) if peer != Some(current) && session.is_connected() => {
                             println!(
-                                "Got answer from webrtcbin, setting local description and sending Signal::Answer to tokio."
+                                "already receiving from producer {}, turning down the offer from {:?}.",
+                                current, peer
                             );
-                            webrtc_bin_clone.emit_by_name::<()>(
-                                "set-local-description",
-                                &[&answer, &None::<gst::Promise>],
+                            let envelope = Envelope {
+                                peer,
+                                signal: Signal::Leave,
+                            };
+                            if sender_clone.send(envelope).await.is_err() {
+                                failure_clone.stop(&main_loop_clone, Error::ChannelClosed);
+                                break;
+                            }
+                            continue;
+                        }
+                        // most likely a producer that restarted before the relay noticed its old
+                        // connection was gone
+                        Some(current) if peer != Some(current) => {
+                            println!(
+                                "producer {} is not connected, switching to {:?}.",
+                                current, peer
                             );
-
-                            sender_clone
-                                .blocking_send(Signal::Answer(answer.sdp().as_text().unwrap()))
-                                .unwrap();
-
-                            println!("Sent to tokio.");
+                            let envelope = Envelope {
+                                peer: Some(current),
+                                signal: Signal::Leave,
+                            };
+                            if sender_clone.send(envelope).await.is_err() {
+                                failure_clone.stop(&main_loop_clone, Error::ChannelClosed);
+                                break;
+                            }
+                            if let Err(err) = setup.restart(&mut session) {
+                                failure_clone.stop(&main_loop_clone, err);
+                                break;
+                            }
+                        }
+                        // a restarted producer session, the old transceivers can't take its offer
+                        Some(_) if session.session_id != session_id => {
+                            println!(
+                                "new session {:?} from producer {:?}, rebuilding webrtcbin.",
+                                session_id, peer
+                            );
+                            if let Err(err) = setup.restart(&mut session) {
+                                failure_clone.stop(&main_loop_clone, err);
+                                break;
+                            }
+                        }
+                        // a fresh session, or a renegotiation of ours
+                        _ => {}
+                    }
+                    session.set_producer(peer);
+                    session.session_id = session_id;
+                    let webrtc_bin_clone = session.webrtc_bin.clone();
+                    let webrtc_bin_clone2 = webrtc_bin_clone.clone();
+
+                    // the configured simulcast layer if the producer offers it, otherwise its
+                    // highest
+                    let layers = offered_layers(&sdp);
+                    if let Some(rid) = &simulcast_layer
+                        && !layers.is_empty()
+                        && !layers.contains(rid)
+                    {
+                        eprintln!("simulcast layer {} not offered, only {:?}.", rid, layers);
+                    }
+                    let layer = match &simulcast_layer {
+                        Some(rid) if layers.contains(rid) => Some(rid.clone()),
+                        _ => layers.first().cloned(),
+                    };
+
+                    let answer_codecs = video_codecs.clone();
+                    let answer_promise = Promise::with_change_func(move |res| {
+                        let option = match res {
+                            Ok(option) => option,
+                            Err(err) => {
+                                eprintln!("webrtcbin could not create an answer: {:?}", err);
+                                return;
+                            }
+                        };
+
+                        let Some(answer) = option.and_then(|val| {
+                            val.get::<gstreamer_webrtc::WebRTCSessionDescription>("answer")
+                                .ok()
+                        }) else {
+                            eprintln!("webrtcbin replied without an answer");
+                            return;
+                        };
+
+                        // webrtcbin keeps the offer's codec order, reorder it so the producer
+                        // sends the codec we prefer
+                        let mut sdp = answer.sdp().to_owned();
+                        match prefer_video_codecs(&mut sdp, &answer_codecs) {
+                            Ok(Some(codec)) => println!("asking for {:?} video.", codec),
+                            Ok(None) => eprintln!("offer has no video codec we can decode."),
+                            Err(err) => {
+                                eprintln!("could not reorder answer codecs: {}", err);
+                                return;
+                            }
+                        }
+                        if let Some(rid) = &layer {
+                            if let Err(err) = receive_only_layer(&mut sdp, rid) {
+                                eprintln!("could not ask for simulcast layer {}: {}", rid, err);
+                                return;
+                            }
+                            println!("asking for simulcast layer {}.", rid);
+                        }
+                        let answer = WebRTCSessionDescription::new(
+                            gstreamer_webrtc::WebRTCSDPType::Answer,
+                            sdp,
+                        );
+
+                        println!(
+                            "Got answer from webrtcbin, setting local description and sending Signal::Answer to tokio."
+                        );
+                        webrtc_bin_clone.emit_by_name::<()>(
+                            "set-local-description",
+                            &[&answer, &None::<gst::Promise>],
+                        );
+
+                        let sdp = match answer.sdp().as_text() {
+                            Ok(sdp) => sdp,
+                            Err(err) => {
+                                eprintln!("could not serialize answer: {}", err);
+                                return;
+                            }
+                        };
+
+                        let envelope = Envelope {
+                            peer,
+                            signal: Signal::Answer(sdp),
+                        };
+                        if sender_clone.blocking_send(envelope).is_err() {
+                            eprintln!("{}, dropping answer", Error::ChannelClosed);
+                            return;
                         }
-                    });
-
-                    println!(
-                        "got offer from producer. setting remote description and creating answer"
-                    );
-
-                    let offer = WebRTCSessionDescription::new(
-                        gstreamer_webrtc::WebRTCSDPType::Offer,
-                        SDPMessage::parse_buffer(sdp.as_bytes()).unwrap(),
-                    );
 
-                    webrtc_bin_clone2.emit_by_name::<()>(
-                        "set-remote-description",
-                        &[&offer, &None::<gst::Promise>],
-                    );
+                        println!("Sent to tokio.");
+                    });
 
+                    println!("got offer from producer. setting remote description and creating answer");
+
+                    let video_mlines: Vec<u32> = (0..sdp.medias_len())
+                        .filter(|idx| {
+                            sdp.media(*idx).and_then(|media| media.media()) == Some("video")
+                        })
+                        .collect();
+                    let offer =
+                        WebRTCSessionDescription::new(gstreamer_webrtc::WebRTCSDPType::Offer, sdp);
+
+                    // the offer's transceivers only exist once it is applied, so codec preferences
+                    // and the answer wait for set-remote-description to finish
+                    let preferences = video_rtp_caps(&video_codecs, false);
+                    let webrtc_bin = webrtc_bin_clone2.clone();
+                    let remote_promise = Promise::with_change_func(move |_| {
+                        set_video_codec_preferences(&webrtc_bin, &video_mlines, &preferences);
+                        webrtc_bin.emit_by_name::<()>(
+                            "create-answer",
+                            &[&None::<gst::Structure>, &answer_promise],
+                        );
+                    });
                     webrtc_bin_clone2
-                        .emit_by_name::<()>("create-answer", &[&None::<gst::Structure>, &promise]);
+                        .emit_by_name::<()>("set-remote-description", &[&offer, &remote_promise]);
                 }
             }
         }
-
-        ControlFlow::Continue
     });
 
-    let main_loop = MainLoop::new(None, false);
     let main_loop_clone = main_loop.clone();
-    let bus = pipeline.bus().unwrap();
+    let failure_clone = failure.clone();
+    let bus = pipeline.bus().expect("a pipeline always has a bus");
+
+    let recorder_clone = recorder.clone();
+    bus.connect_message(Some("error"), move |_, msg| {
+        // a failed recording is dropped on its own, playback carries on
+        if recorder_clone.lock().unwrap().handle_message(msg) {
+            return;
+        }
 
-    bus.connect_message(Some("error"), move |_, msg| match msg.view() {
-        MessageView::Error(err) => {
+        if let MessageView::Error(err) = msg.view() {
             eprintln!("Error message received from bus: {:?}", err);
-            main_loop_clone.quit();
-        }
-        MessageView::Eos(..) => {
-            main_loop_clone.quit();
+            let source = err
+                .src()
+                .map(|src| src.path_string().to_string())
+                .unwrap_or_default();
+            let message = err.error().to_string();
+            failure_clone.stop(&main_loop_clone, Error::Pipeline { source, message });
         }
-        _ => unreachable!(),
+    });
+
+    // every sink has drained, as begin_shutdown waits for
+    let main_loop_clone = main_loop.clone();
+    bus.connect_message(Some("eos"), move |_, _| {
+        println!("pipeline drained.");
+        main_loop_clone.quit();
+    });
+
+    let recorder_clone = recorder.clone();
+    bus.connect_message(Some("element"), move |_, msg| {
+        recorder_clone.lock().unwrap().handle_message(msg);
     });
 
     bus.add_signal_watch();
-    pipeline.set_state(State::Playing).unwrap();
-    main_loop.run();
-    pipeline.set_state(State::Null).unwrap();
+    let started = set_state(&pipeline, State::Playing);
+    if started.is_ok() {
+        main_loop.run();
+        // the muxers need the pipeline still playing to write out their files
+        recorder.lock().unwrap().finish(&bus);
+    }
+    // nothing polls the signal task once the main loop is gone. dropping it drops its sender,
+    // which is what ends the signaling side
+    signal_task.abort();
+
+    // tear down even if starting failed, the sinks may already hold their devices
+    let stopped = set_state(&pipeline, State::Null);
     bus.remove_signal_watch();
+
+    started?;
+    if let Some(err) = failure.take() {
+        return Err(err);
+    }
+    stopped
+}
+
+// one producer's WebRTC session: webrtcbin and everything its incoming streams were linked into
+struct Session {
+    webrtc_bin: Element,
+    // the producer whose offer we answered, our candidates are addressed to it
+    producer: Arc<Mutex<Option<PeerId>>>,
+    // the tees, queues and decodebins added for its streams
+    elements: Arc<Mutex<Vec<Element>>>,
+    // the session id of the offer we answered, from its SDP origin line
+    session_id: Option<String>,
+}
+
+impl Session {
+    fn producer(&self) -> Option<PeerId> {
+        *self.producer.lock().unwrap()
+    }
+
+    fn set_producer(&self, peer: Option<PeerId>) {
+        *self.producer.lock().unwrap() = peer;
+    }
+
+    fn is_connected(&self) -> bool {
+        self.webrtc_bin
+            .property::<WebRTCPeerConnectionState>("connection-state")
+            == WebRTCPeerConnectionState::Connected
+    }
+}
+
+// what every session's webrtcbin is connected to, kept from one session to the next
+struct SessionSetup {
+    pipeline: Pipeline,
+    ice: IceConfig,
+    // the converters decoded streams are linked to, None when nothing is decoded
+    converters: Option<(Element, Element)>,
+    recorder: Arc<Mutex<Recorder>>,
+    record_from_start: bool,
+    on_ice_connection_state: Option<IceStateCallback>,
+    send_to_tokio: Sender<Envelope>,
+    started: u32,
+}
+
+type IceStateCallback = Arc<Mutex<Box<dyn FnMut(WebRTCICEConnectionState) + Send>>>;
+
+impl SessionSetup {
+    // a fresh webrtcbin in the playing pipeline, waiting for an offer
+    fn start(&mut self) -> Result<Session, Error> {
+        self.started += 1;
+        let webrtc_bin = make_element("webrtcbin")?;
+        configure_ice(&webrtc_bin, &self.ice);
+        if let Some(on_ice_connection_state) = &self.on_ice_connection_state {
+            watch_ice_connection_state(&webrtc_bin, on_ice_connection_state.clone());
+        }
+
+        let session = Session {
+            webrtc_bin,
+            producer: Arc::new(Mutex::new(None)),
+            elements: Arc::new(Mutex::new(Vec::new())),
+            session_id: None,
+        };
+
+        session.webrtc_bin.connect_notify(None, |x, y| {
+            println!("notify called");
+            println!("{:?}", y.name());
+            let sig = x.property::<gstreamer_webrtc::WebRTCSignalingState>("signaling-state");
+            let ice =
+                x.property::<gstreamer_webrtc::WebRTCICEGatheringState>("ice-gathering-state");
+            println!("signaling-state: {:?}", sig);
+            println!("ice-gathering-state: {:?}", ice);
+        });
+
+        // forgot this in prev part
+        let sender_clone = self.send_to_tokio.clone();
+        let producer_clone = session.producer.clone();
+        session
+            .webrtc_bin
+            .connect("on-ice-candidate", false, move |values| {
+                println!("on ice candidate event, sending to peer.");
+
+                let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
+                let mline_index = values[1].get::<u32>().expect("Invalid argument");
+                let candidate = values[2].get::<String>().expect("Invalid argument");
+
+                println!("mline_index: {}", mline_index);
+                println!("candidate: {}", candidate);
+
+                let envelope = Envelope {
+                    peer: *producer_clone.lock().unwrap(),
+                    signal: Signal::IceCandidate {
+                        mline_index,
+                        candidate,
+                    },
+                };
+                if sender_clone.blocking_send(envelope).is_err() {
+                    eprintln!("{}, dropping ICE candidate", Error::ChannelClosed);
+                }
+
+                None
+            });
+
+        let converters = self.converters.clone();
+        let recorder_clone = self.recorder.clone();
+        let elements_clone = session.elements.clone();
+        let record_from_start = self.record_from_start;
+        // the pipeline is looked up rather than captured, which would keep it alive forever
+        session
+            .webrtc_bin
+            .connect_pad_added(move |webrtc_bin, pad| {
+                println!("Pad added to webrtc_bin: {}", pad.name());
+                let Some(pipeline) = webrtc_bin.parent().and_downcast::<Pipeline>() else {
+                    eprintln!(
+                        "webrtcbin is no longer in the pipeline, ignoring {}",
+                        pad.name()
+                    );
+                    return;
+                };
+                if pad.direction() == PadDirection::Src
+                    && let Err(err) = split_webrtc_pad(
+                        &pipeline,
+                        pad,
+                        converters.clone(),
+                        &recorder_clone,
+                        record_from_start,
+                        &elements_clone,
+                    )
+                {
+                    eprintln!("Error handling incoming stream: {}", err);
+                }
+            });
+
+        self.pipeline.add(&session.webrtc_bin)?;
+        session.webrtc_bin.sync_state_with_parent()?;
+        println!("session {} waiting for an offer.", self.started);
+        Ok(session)
+    }
+
+    // takes webrtcbin and its streams out of the pipeline. the converters and sinks stay, their
+    // sink pads free for the next session's decoders
+    fn end(&self, session: Session) {
+        // finalizes the recording of this session's streams, the next one gets a file of its own
+        self.recorder.lock().unwrap().clear_streams();
+
+        let elements = std::mem::take(&mut *session.elements.lock().unwrap());
+        for element in std::iter::once(session.webrtc_bin).chain(elements) {
+            if let Err(err) = set_state(&element, State::Null) {
+                eprintln!("Error stopping {}: {}", element.name(), err);
+            }
+            if let Err(err) = self.pipeline.remove(&element) {
+                eprintln!("Error removing {}: {}", element.name(), err);
+            }
+        }
+    }
+
+    // ends the session and starts a new one in its place
+    fn restart(&mut self, session: &mut Session) -> Result<(), Error> {
+        let ended = std::mem::replace(session, self.start()?);
+        self.end(ended);
+        Ok(())
+    }
+}
+
+// limits the offer's video transceivers to the codecs we decode, in our preference order.
+// transceivers created from an offer are matched to its m-lines by mlineindex
+fn set_video_codec_preferences(
+    webrtc_bin: &Element,
+    video_mlines: &[u32],
+    preferences: &gst::Caps,
+) {
+    let mut idx = 0i32;
+    while let Some(transceiver) =
+        webrtc_bin.emit_by_name::<Option<WebRTCRTPTransceiver>>("get-transceiver", &[&idx])
+    {
+        if video_mlines.contains(&transceiver.property::<u32>("mlineindex")) {
+            transceiver.set_property("codec-preferences", preferences);
+        }
+        idx += 1;
+    }
+}
+
+// tees one of webrtcbin's incoming RTP streams to the decoder, when there is one, and to the
+// recorder. recording from the start begins once both audio and video have arrived
+fn split_webrtc_pad(
+    pipeline: &Pipeline,
+    pad: &Pad,
+    converters: Option<(Element, Element)>,
+    recorder: &Mutex<Recorder>,
+    record_from_start: bool,
+    elements: &Mutex<Vec<Element>>,
+) -> Result<(), Error> {
+    let caps = pad.current_caps().unwrap_or_else(|| pad.query_caps(None));
+    let media = recorded_media(&caps);
+
+    let tee = make_element("tee")?;
+    // the recorder may not be linked yet
+    tee.set_property("allow-not-linked", true);
+    pipeline.add(&tee)?;
+    elements.lock().unwrap().push(tee.clone());
+
+    if let Some((audio_converter, video_converter)) = converters {
+        let queue = make_element("queue")?;
+        pipeline.add(&queue)?;
+        elements.lock().unwrap().push(queue.clone());
+        let tee_pad = tee
+            .request_pad_simple("src_%u")
+            .ok_or_else(|| Error::Link("tee: no free src pad".to_string()))?;
+        link_pads(
+            &tee_pad,
+            &queue.static_pad("sink").expect("queue has a sink pad"),
+        )?;
+        queue.sync_state_with_parent()?;
+        let decode_bin = decode_webrtc_pad(
+            pipeline,
+            &queue.static_pad("src").expect("queue has a src pad"),
+            audio_converter,
+            video_converter,
+        )?;
+        elements.lock().unwrap().push(decode_bin);
+    }
+
+    tee.sync_state_with_parent()?;
+    link_pads(pad, &tee.static_pad("sink").expect("tee has a sink pad"))?;
+
+    let Some(media) = media else {
+        eprintln!("not recording stream with caps {}", caps);
+        return Ok(());
+    };
+    let mut recorder = recorder.lock().unwrap();
+    recorder.add_stream(tee, media);
+    if record_from_start && recorder.stream_count() == 2 {
+        recorder.start(pipeline)?;
+    }
+    Ok(())
+}
+
+// decodes one of webrtcbin's incoming streams and links it into the matching converter,
+// returning the decodebin
+fn decode_webrtc_pad(
+    pipeline: &Pipeline,
+    pad: &Pad,
+    audio_converter: Element,
+    video_converter: Element,
+) -> Result<Element, Error> {
+    let decode_bin = make_element("decodebin")?;
+    decode_bin.connect_pad_added(move |_src, src_pad| {
+        println!("pad added to decodebin");
+        let Some(new_pad_caps) = src_pad.current_caps() else {
+            eprintln!("decodebin pad has no caps");
+            return;
+        };
+        let Some(new_pad_struct) = new_pad_caps.structure(0) else {
+            return;
+        };
+        let new_pad_type = new_pad_struct.name();
+
+        let is_video = new_pad_type.starts_with("video/x-raw");
+        let is_audio = new_pad_type.starts_with("audio/x-raw");
+
+        let el: Option<&Element> = if is_audio {
+            Some(&audio_converter)
+        } else if is_video {
+            Some(&video_converter)
+        } else {
+            None
+        };
+
+        if let Some(converter_sink_pad) = el.and_then(|el| el.static_pad("sink"))
+            && !converter_sink_pad.is_linked()
+        {
+            match link_pads(src_pad, &converter_sink_pad) {
+                Ok(()) => println!("Converter sink pad linked"),
+                Err(err) => eprintln!("Error linking pad: {}", err),
+            }
+        }
+    });
+
+    pipeline.add(&decode_bin)?;
+    decode_bin.sync_state_with_parent()?;
+    let sink_pad = decode_bin
+        .static_pad("sink")
+        .expect("decodebin has a sink pad");
+    link_pads(pad, &sink_pad)?;
+    Ok(decode_bin)
+}
+
+// appsinks with fixed raw formats, so callbacks don't have to handle whatever the decoder picked
+fn make_app_sinks(
+    mut on_video_frame: Box<dyn FnMut(VideoFrame) + Send>,
+    mut on_audio_samples: Box<dyn FnMut(AudioSamples) + Send>,
+) -> Result<(Element, Element), Error> {
+    let audio_sink = make_element("appsink")?;
+    let video_sink = make_element("appsink")?;
+
+    let audio_caps = gst::Caps::builder("audio/x-raw")
+        .field("format", "F32LE")
+        .field("layout", "interleaved")
+        .build();
+    audio_sink.set_property("caps", &audio_caps);
+    audio_sink.set_property("sync", false);
+    audio_sink
+        .downcast_ref::<AppSink>()
+        .expect("appsink is an AppSink")
+        .set_callbacks(
+            AppSinkCallbacks::builder()
+                .new_sample(move |app_sink| {
+                    let sample = app_sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let (Some(buffer), Some(caps)) = (sample.buffer_owned(), sample.caps_owned())
+                    else {
+                        return Err(gst::FlowError::Error);
+                    };
+
+                    on_audio_samples(AudioSamples {
+                        pts: buffer.pts(),
+                        caps,
+                        buffer,
+                    });
+
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+    let video_caps = gst::Caps::builder("video/x-raw")
+        .field("format", "RGB")
+        .build();
+    video_sink.set_property("caps", &video_caps);
+    video_sink.set_property("sync", false);
+    video_sink
+        .downcast_ref::<AppSink>()
+        .expect("appsink is an AppSink")
+        .set_callbacks(
+            AppSinkCallbacks::builder()
+                .new_sample(move |app_sink| {
+                    let sample = app_sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let (Some(buffer), Some(caps)) = (sample.buffer_owned(), sample.caps_owned())
+                    else {
+                        return Err(gst::FlowError::Error);
+                    };
+
+                    on_video_frame(VideoFrame {
+                        pts: buffer.pts(),
+                        caps,
+                        buffer,
+                    });
+
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+    Ok((audio_sink, video_sink))
+}
+
+// notify handlers may run on any thread, so the FnMut has a lock around it. it is shared by
+// every session's webrtcbin
+fn watch_ice_connection_state(webrtc_bin: &Element, on_ice_connection_state: IceStateCallback) {
+    webrtc_bin.connect_notify(Some("ice-connection-state"), move |webrtc_bin, _| {
+        let state = webrtc_bin.property::<WebRTCICEConnectionState>("ice-connection-state");
+        (on_ice_connection_state.lock().unwrap())(state);
+    });
 }
@@ -1,204 +1,960 @@
-use gst::glib::MainLoop;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use gst::glib::{self, MainContext, MainLoop};
 use gst::prelude::*;
-use gst::{Element, ElementFactory, MessageView, Pipeline, State};
-use gstreamer::glib::{ControlFlow, source};
+use gst::{Element, MessageView, Pad, Pipeline, State};
 use gstreamer::{self as gst, Promise};
-use gstreamer_webrtc::WebRTCSessionDescription;
-use gstreamer_webrtc::gst_sdp::SDPMessage;
+use gstreamer_webrtc::{
+    WebRTCICEConnectionState, WebRTCPeerConnectionState, WebRTCRTPTransceiver,
+    WebRTCRTPTransceiverDirection, WebRTCSDPType, WebRTCSessionDescription, WebRTCSignalingState,
+};
 use tokio::sync::mpsc::{Receiver, Sender};
 
-use crate::{HOST, Signal};
+use crate::bitrate::{BitrateController, LinkStats};
+use crate::config::{Config, IceConfig, SourceConfig, SourceKind, VideoCodec};
+use crate::error::{Error, Failure, link_many, link_pads, make_element, parse_sdp, set_state};
+use crate::mediacodecs::{
+    make_twcc_filter, make_video_encoder, make_video_payloader, negotiated_video_codec,
+    set_video_bitrate, video_rtp_caps,
+};
+use crate::mediarecorder::{RecordedMedia, Recorder};
+use crate::mediashutdown::{begin_shutdown, say_bye};
+use crate::mediasimulcast::{
+    LAYERS, Layer, add_rid_extension, add_simulcast_offer, answered_layer, make_layer_scaler,
+};
+use crate::mediastats::link_stats;
+use crate::webrtc::configure_ice;
+use crate::{Envelope, PeerId, Signal};
+
+// how often the viewers' stats are collected and the encoder bitrates adjusted
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+// how long a viewer's link may stay disconnected before an ICE restart, it often recovers by itself
+const DISCONNECTED_GRACE: Duration = Duration::from_secs(2);
+// how long an ICE restart gets to bring the link back before the next one
+const ICE_RESTART_INTERVAL: Duration = Duration::from_secs(10);
+
+// one encoded video stream a viewer can be linked to
+#[derive(Clone, PartialEq)]
+struct VideoLayer {
+    codec: VideoCodec,
+    // the simulcast layer, None without simulcast
+    rid: Option<&'static str>,
+    tee: Element,
+}
+
+impl VideoLayer {
+    fn name(&self) -> String {
+        match self.rid {
+            Some(rid) => format!("{:?} layer {}", self.codec, rid),
+            None => format!("{:?}", self.codec),
+        }
+    }
+}
+
+// one encoding of the raw video, shared by every viewer whose answer picked its layer
+struct VideoEncoder {
+    layer: VideoLayer,
+    encoder: Element,
+    bitrate: BitrateController,
+}
+
+// a viewer's webrtcbin and the queues feeding it from the shared encoder tees
+struct ViewerBranch {
+    webrtc_bin: Element,
+    audio_queue: Element,
+    audio_tee_pad: Pad,
+    // the encoded video offered to this viewer, in our preference order
+    offered: Vec<VideoLayer>,
+    // linked once the viewer's answer has picked one of the offered codecs and layers
+    video: Option<VideoLink>,
+    // filled in by the latest get-stats reply, taken each time the bitrates are adjusted
+    stats: Arc<Mutex<Option<LinkStats>>>,
+    // kept up to date by webrtcbin's state notifications
+    connectivity: Arc<Mutex<Connectivity>>,
+}
+
+// whether a viewer's link is up, from its ice-connection-state and connection-state
+#[derive(Default)]
+struct Connectivity {
+    // when the link went disconnected or failed, None while it is up
+    lost_since: Option<Instant>,
+    failed: bool,
+    last_restart: Option<Instant>,
+}
+
+struct VideoLink {
+    layer: VideoLayer,
+    tee_pad: Pad,
+    queue: Element,
+}
 
-// This part swapped the uridecodebin with camera and microphone sources
-pub fn run_producer_pipeline(send_to_tokio: Sender<Signal>, mut gst_recv: Receiver<Signal>) {
-    gst::init().unwrap();
+// Raw audio and video come from the camera/mic, test sources or a file depending on config
+// Encoding happens once per configured video codec and simulcast layer, each viewer gets its own
+// webrtcbin branch off the audio tee and the tee of whichever codec and layer its answer picked
+// Runs until the pipeline errors, the signaling channel closes or a Shutdown signal has drained it
+pub fn run_producer_pipeline(
+    config: &Config,
+    send_to_tokio: Sender<Envelope>,
+    gst_recv: Receiver<Envelope>,
+) -> Result<(), Error> {
+    gst::init().map_err(Error::Init)?;
 
+    // each pipeline drives its own main context, so a producer and a consumer can share a process
+    let context = MainContext::new();
+    context.with_thread_default(|| producer_pipeline(&context, config, send_to_tokio, gst_recv))?
+}
+
+fn producer_pipeline(
+    context: &MainContext,
+    config: &Config,
+    send_to_tokio: Sender<Envelope>,
+    mut gst_recv: Receiver<Envelope>,
+) -> Result<(), Error> {
     let pipeline = Pipeline::with_name("pipeline");
 
-    let camera = ElementFactory::make("v4l2src").build().unwrap();
-    let mic = ElementFactory::make("pulsesrc").build().unwrap();
-    let audio_converter = ElementFactory::make("audioconvert").build().unwrap();
-    let video_converter = ElementFactory::make("videoconvert").build().unwrap();
-    let audio_resampler = ElementFactory::make("audioresample").build().unwrap();
-    let audio_encoder = ElementFactory::make("opusenc").build().unwrap();
-    let video_encoder = ElementFactory::make("x264enc").build().unwrap();
-    let audio_payloader = ElementFactory::make("rtpopuspay").build().unwrap();
-    let video_payloader = ElementFactory::make("rtph264pay").build().unwrap();
-    let webrtc_bin = ElementFactory::make("webrtcbin")
-        .property_from_str("stun-server", format!("stun://{}:3478", HOST).as_str())
-        .property_from_str(
-            "turn-server",
-            format!("turn://test:test@{}:3478", HOST).as_str(),
-        )
-        .build()
-        .unwrap();
-
-    // configure the encoder properties for low latency
-    video_encoder.set_property_from_str("speed-preset", "ultrafast");
-    video_encoder.set_property_from_str("tune", "zerolatency");
-    video_encoder.set_property("intra-refresh", true);
-    video_encoder.set_property_from_str("key-int-max", "15"); // Set I-frame interval to 15 frames
-
-    pipeline
-        .add_many([
-            &camera,
-            &mic,
-            &audio_converter,
-            &video_converter,
-            &audio_resampler,
-            &audio_encoder,
-            &video_encoder,
-            &audio_payloader,
-            &video_payloader,
-            &webrtc_bin,
-        ])
-        .unwrap();
-
-    Element::link_many([
-        &mic,
+    let audio_converter = make_element("audioconvert")?;
+    let video_converter = make_element("videoconvert")?;
+    let audio_resampler = make_element("audioresample")?;
+    let audio_encoder = make_element("opusenc")?;
+    let audio_payloader = make_element("rtpopuspay")?;
+    let audio_tee = make_element("tee")?;
+    // raw video, split once per encoder
+    let raw_video_tee = make_element("tee")?;
+
+    // keep encoding while nobody is watching
+    audio_tee.set_property("allow-not-linked", true);
+
+    pipeline.add_many([
         &audio_converter,
+        &video_converter,
         &audio_resampler,
-        // rate
         &audio_encoder,
         &audio_payloader,
-        &webrtc_bin,
-    ])
-    .unwrap();
+        &audio_tee,
+        &raw_video_tee,
+    ])?;
 
-    Element::link_many([
-        &camera,
-        &video_converter,
-        // scaler
+    link_many(&[
+        &audio_converter,
+        &audio_resampler,
         // rate
-        &video_encoder,
-        &video_payloader,
-        &webrtc_bin,
-    ])
-    .unwrap();
+        &audio_encoder,
+        &audio_payloader,
+        &audio_tee,
+    ])?;
 
-    webrtc_bin.connect_notify(None, |x, y| {
-        println!("notify called");
-        println!("{:?}", y.name());
-        let sig = x.property::<gstreamer_webrtc::WebRTCSignalingState>("signaling-state");
-        let ice = x.property::<gstreamer_webrtc::WebRTCICEGatheringState>("ice-gathering-state");
-        println!("signaling-state: {:?}", sig);
-        println!("ice-gathering-state: {:?}", ice);
-    });
+    link_many(&[&video_converter, &raw_video_tee])?;
 
-    let webrtc_bin_clone = webrtc_bin.clone();
-    let sender_clone = send_to_tokio.clone();
-    webrtc_bin.connect("on-negotiation-needed", false, move |_| {
-        println!("Negotiation needed from webrtcbin");
-        let webrtc_bin_clone = webrtc_bin_clone.clone();
-        let webrtc_bin_clone_clone = webrtc_bin_clone.clone();
-        let sender_clone = sender_clone.clone();
-        let promise = Promise::with_change_func(move |res| {
-            let option = res.unwrap();
-
-            if let Some(val) = option {
-                let offer = val
-                    .get::<gstreamer_webrtc::WebRTCSessionDescription>("offer")
-                    .unwrap();
-
-                println!(
-                    "Got offer from webrtcbin, setting local description and sending Signal::Offer to tokio."
-                );
-                webrtc_bin_clone
-                    .emit_by_name::<()>("set-local-description", &[&offer, &None::<gst::Promise>]);
-
-                sender_clone
-                    .blocking_send(Signal::Offer(offer.sdp().as_text().unwrap()))
-                    .unwrap();
-
-                println!("Sent to tokio.");
-            }
-        });
+    if config.video.codecs.is_empty() {
+        return Err(Error::InvalidConfig(
+            "the producer needs at least one video codec".to_string(),
+        ));
+    }
 
-        println!("Telling webrtcbin to create an offer");
-        webrtc_bin_clone_clone
-            .emit_by_name::<()>("create-offer", &[&None::<gst::Structure>, &promise.clone()]);
-        None
-    });
+    let video = &config.video;
+    video.validate().map_err(Error::InvalidConfig)?;
 
-    let sender_clone = send_to_tokio.clone();
-    webrtc_bin.connect("on-ice-candidate", false, move |values| {
-        println!("on ice candidate event, sending to peer.");
+    // without simulcast each codec is a single full size layer
+    let layers: Vec<Option<Layer>> = if video.simulcast {
+        LAYERS.map(Some).to_vec()
+    } else {
+        vec![None]
+    };
 
-        let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
-        let mline_index = values[1].get::<u32>().expect("Invalid argument");
-        let candidate = values[2].get::<String>().expect("Invalid argument");
+    // in preference order, each viewer is offered the ones it can decode
+    let mut video_encoders: Vec<VideoEncoder> = Vec::new();
+    for &codec in &video.codecs {
+        for &layer in &layers {
+            let (encoder, tee) =
+                add_video_encoder(&pipeline, &raw_video_tee, codec, layer, &config.source)?;
 
-        println!("mline_index: {}", mline_index);
-        println!("candidate: {}", candidate);
+            // a layer at 1/scale the width and height needs about 1/scale² of the bits
+            let scale = layer.map_or(1, |layer| layer.scale);
+            let per_layer = |kbps: u32| (kbps / (scale * scale)).max(1);
+            let bitrate = BitrateController::new(
+                per_layer(video.min_bitrate),
+                per_layer(video.start_bitrate),
+                per_layer(video.max_bitrate),
+            );
+            set_video_bitrate(&encoder, codec, bitrate.current());
 
-        sender_clone
-            .blocking_send(Signal::IceCandidate {
-                mline_index,
-                candidate,
-            })
-            .unwrap();
+            video_encoders.push(VideoEncoder {
+                layer: VideoLayer {
+                    codec,
+                    rid: layer.map(|layer| layer.rid),
+                    tee,
+                },
+                encoder,
+                bitrate,
+            });
+        }
+    }
 
-        None
-    });
+    // records the first codec's highest layer along with the audio, depayloaded as they are
+    let recorder = Arc::new(Mutex::new(Recorder::new(&config.recording, "producer")));
+    {
+        let mut recorder = recorder.lock().unwrap();
+        let recorded = &video_encoders[0].layer;
+        recorder.add_stream(recorded.tee.clone(), RecordedMedia::Video(recorded.codec));
+        recorder.add_stream(audio_tee.clone(), RecordedMedia::Opus);
+        if config.recording.enabled {
+            recorder.start(&pipeline)?;
+        }
+    }
 
-    let webrtc_bin_clone = webrtc_bin.clone();
-    source::idle_add(move || {
-        let msg_result = gst_recv.try_recv();
-
-        if let Ok(signal) = msg_result {
-            match signal {
-                Signal::IceCandidate {
-                    mline_index,
-                    candidate,
-                } => {
+    add_sources(
+        &pipeline,
+        &config.source,
+        &audio_converter,
+        &video_converter,
+    )?;
+
+    let main_loop = MainLoop::new(Some(context), false);
+    let failure = Failure::default();
+
+    let pipeline_clone = pipeline.clone();
+    let main_loop_clone = main_loop.clone();
+    let failure_clone = failure.clone();
+    let ice = config.ice.clone();
+    let recorder_clone = recorder.clone();
+    let context_clone = context.clone();
+    let mut viewers: HashMap<PeerId, ViewerBranch> = HashMap::new();
+    let mut shutting_down = false;
+    // woken by each signal from tokio and by the stats tick, the main context sleeps in between
+    let signal_task = context.spawn_local(async move {
+        let mut stats_ticks = glib::interval_stream(STATS_INTERVAL);
+        loop {
+            let received = tokio::select! {
+                biased;
+                Some(()) = stats_ticks.next() => {
+                    adapt_bitrates(&mut video_encoders, &viewers);
+                    for (peer, branch) in &viewers {
+                        request_stats(branch);
+                        restart_ice_if_lost(*peer, branch, &send_to_tokio);
+                    }
+                    continue;
+                }
+                received = gst_recv.recv() => received,
+            };
+            let Some(Envelope { peer, signal }) = received else {
+                failure_clone.stop(&main_loop_clone, Error::ChannelClosed);
+                break;
+            };
+
+            // nothing new is set up while the pipeline drains
+            if shutting_down {
+                println!("shutting down, ignoring {:?} from {:?}.", signal, peer);
+                continue;
+            }
+
+            match (signal, peer) {
+                (Signal::Reconnected, None) => {
+                    // peer ids from the old connection are gone, the relay announces the room again
+                    println!("signaling reconnected. dropping all viewer branches.");
+                    for (_, branch) in viewers.drain() {
+                        remove_viewer(&pipeline_clone, &audio_tee, branch);
+                    }
+                }
+                (Signal::StartRecording, None) => {
+                    // a recording that won't start leaves the live stream as it was
+                    if let Err(err) = recorder_clone.lock().unwrap().start(&pipeline_clone) {
+                        eprintln!("{}", err);
+                    }
+                }
+                (Signal::StopRecording, None) => {
+                    recorder_clone.lock().unwrap().stop();
+                }
+                (Signal::Shutdown, None) => {
+                    shutting_down = true;
+                    say_bye(&send_to_tokio, None).await;
+                    begin_shutdown(&pipeline_clone, &main_loop_clone, &context_clone);
+                }
+                // a custom signaling transport could pass them on, only the local side may send them
+                (
+                    Signal::Reconnected
+                    | Signal::StartRecording
+                    | Signal::StopRecording
+                    | Signal::Shutdown,
+                    Some(peer),
+                ) => {
+                    println!("ignoring local signal from peer {}.", peer);
+                }
+                (signal, None) => {
+                    println!("ignoring signal without a peer: {:?}", signal);
+                }
+                (Signal::PeerJoined, Some(peer)) => {
+                    // only consumers send capabilities, that is what makes a peer a viewer
+                    println!("peer {} joined. waiting for its capabilities.", peer);
+                }
+                (Signal::Capabilities { video_codecs }, Some(peer)) => {
+                    if !viewers.contains_key(&peer) {
+                        // everything we encode that the viewer can decode, the answer picks from
+                        // these
+                        let offered: Vec<VideoLayer> = video_encoders
+                            .iter()
+                            .filter(|encoder| video_codecs.contains(&encoder.layer.codec))
+                            .map(|encoder| encoder.layer.clone())
+                            .collect();
+                        if offered.is_empty() {
+                            eprintln!(
+                                "viewer {} can only decode {:?}, none of which we encode.",
+                                peer, video_codecs
+                            );
+                            continue;
+                        }
+
+                        println!(
+                            "viewer {} joined. adding a webrtcbin branch offering {:?} video.",
+                            peer,
+                            offered_codecs(&offered)
+                        );
+                        let result = add_viewer(
+                            &pipeline_clone,
+                            &audio_tee,
+                            offered,
+                            peer,
+                            &ice,
+                            &send_to_tokio,
+                        );
+
+                        // a branch that can't be built now won't build for the next viewer either
+                        match result {
+                            Ok(branch) => {
+                                viewers.insert(peer, branch);
+                            }
+                            Err(err) => {
+                                failure_clone.stop(&main_loop_clone, err);
+                                break;
+                            }
+                        }
+                    }
+                }
+                (Signal::PeerLeft | Signal::Bye | Signal::Leave, Some(peer)) => {
+                    if let Some(branch) = viewers.remove(&peer) {
+                        println!("viewer {} left. removing its webrtcbin branch.", peer);
+                        remove_viewer(&pipeline_clone, &audio_tee, branch);
+                    }
+                }
+                (
+                    Signal::IceCandidate {
+                        mline_index,
+                        candidate,
+                    },
+                    Some(peer),
+                ) => {
                     println!(
-                        "Ice candidate received. mline_index: {}, candidate: {}. setting on webrtcbin.",
-                        mline_index, candidate
+                        "Ice candidate received from {}. mline_index: {}, candidate: {}. setting on webrtcbin.",
+                        peer, mline_index, candidate
                     );
 
-                    webrtc_bin_clone
-                        .emit_by_name::<()>("add-ice-candidate", &[&mline_index, &candidate]);
+                    if let Some(branch) = viewers.get(&peer) {
+                        branch
+                            .webrtc_bin
+                            .emit_by_name::<()>("add-ice-candidate", &[&mline_index, &candidate]);
+                    }
                 }
-                Signal::Answer(sdp) => {
-                    println!("got answer from consumer. setting remote description.");
-                    let answer = WebRTCSessionDescription::new(
-                        gstreamer_webrtc::WebRTCSDPType::Answer,
-                        SDPMessage::parse_buffer(sdp.as_bytes()).unwrap(),
+                (Signal::Answer(sdp), Some(peer)) => {
+                    println!(
+                        "got answer from consumer {}. setting remote description.",
+                        peer
                     );
 
-                    webrtc_bin_clone.emit_by_name::<()>(
-                        "set-remote-description",
-                        &[&answer, &None::<gst::Promise>],
-                    );
+                    if let Some(branch) = viewers.get_mut(&peer) {
+                        // a bad answer only costs that viewer its stream
+                        let sdp = match parse_sdp(&sdp) {
+                            Ok(sdp) => sdp,
+                            Err(err) => {
+                                eprintln!("ignoring answer from consumer {}: {}", peer, err);
+                                continue;
+                            }
+                        };
+                        let codec = negotiated_video_codec(&sdp);
+                        let rid = answered_layer(&sdp);
+                        let answer =
+                            WebRTCSessionDescription::new(WebRTCSDPType::Answer, sdp);
+
+                        branch.webrtc_bin.emit_by_name::<()>(
+                            "set-remote-description",
+                            &[&answer, &None::<gst::Promise>],
+                        );
+
+                        // renegotiations keep the encoder the first answer picked
+                        if branch.video.is_none() {
+                            let result = match codec {
+                                Some(codec) => {
+                                    link_video(&pipeline_clone, branch, codec, rid.as_deref())
+                                }
+                                None => Err(Error::InvalidSdp(
+                                    "answer has no video codec we offered".to_string(),
+                                )),
+                            };
+                            match result {
+                                Ok(layer) => {
+                                    println!("viewer {} picked {} video.", peer, layer.name())
+                                }
+                                Err(err) => eprintln!("viewer {} gets no video: {}", peer, err),
+                            }
+                        }
+                    }
                 }
-                Signal::Offer(_sdp) => {
+                (Signal::Offer(_sdp), Some(_)) => {
                     println!("should not get offer in producer.");
                 }
             }
         }
-
-        ControlFlow::Continue
     });
 
-    let main_loop = MainLoop::new(None, false);
     let main_loop_clone = main_loop.clone();
-    let bus = pipeline.bus().unwrap();
+    let failure_clone = failure.clone();
+    let recorder_clone = recorder.clone();
+    let bus = pipeline.bus().expect("a pipeline always has a bus");
 
-    bus.connect_message(Some("error"), move |_, msg| match msg.view() {
-        MessageView::Error(err) => {
-            eprintln!("Error message received from bus: {:?}", err);
-            main_loop_clone.quit();
+    bus.connect_message(Some("error"), move |_, msg| {
+        // a failed recording is dropped on its own, the live stream carries on
+        if recorder_clone.lock().unwrap().handle_message(msg) {
+            return;
         }
-        MessageView::Eos(..) => {
-            main_loop_clone.quit();
+
+        if let MessageView::Error(err) = msg.view() {
+            eprintln!("Error message received from bus: {:?}", err);
+            let source = err
+                .src()
+                .map(|src| src.path_string().to_string())
+                .unwrap_or_default();
+            let message = err.error().to_string();
+            failure_clone.stop(&main_loop_clone, Error::Pipeline { source, message });
         }
-        _ => unreachable!(),
+    });
+
+    // every sink has drained, as begin_shutdown waits for
+    let main_loop_clone = main_loop.clone();
+    bus.connect_message(Some("eos"), move |_, _| {
+        println!("pipeline drained.");
+        main_loop_clone.quit();
+    });
+
+    let recorder_clone = recorder.clone();
+    bus.connect_message(Some("element"), move |_, msg| {
+        recorder_clone.lock().unwrap().handle_message(msg);
     });
 
     bus.add_signal_watch();
-    pipeline.set_state(State::Playing).unwrap();
+    let started = set_state(&pipeline, State::Playing);
+    if started.is_ok() {
+        main_loop.run();
+        // the muxers need the pipeline still playing to write out their files
+        recorder.lock().unwrap().finish(&bus);
+    }
+    // nothing polls the signal task once the main loop is gone. dropping it drops its sender,
+    // which is what ends the signaling side
+    signal_task.abort();
 
-    main_loop.run();
-    pipeline.set_state(State::Null).unwrap();
+    // tear down even if starting failed, some elements may already hold their devices
+    let stopped = set_state(&pipeline, State::Null);
     bus.remove_signal_watch();
+
+    started?;
+    if let Some(err) = failure.take() {
+        return Err(err);
+    }
+    stopped
+}
+
+// adds the configured capture elements and links them into the converters
+fn add_sources(
+    pipeline: &Pipeline,
+    source_config: &SourceConfig,
+    audio_converter: &Element,
+    video_converter: &Element,
+) -> Result<(), Error> {
+    match source_config.kind {
+        SourceKind::Device => {
+            let camera = make_element("v4l2src")?;
+            let mic = make_element("pulsesrc")?;
+
+            pipeline.add_many([&camera, &mic])?;
+            link_many(&[&camera, video_converter])?;
+            link_many(&[&mic, audio_converter])?;
+        }
+        SourceKind::Test => {
+            // is-live makes the test sources produce buffers in real time like a capture device
+            let video_source = make_element("videotestsrc")?;
+            video_source.set_property("is-live", true);
+            video_source.set_property_from_str("pattern", &source_config.pattern);
+            let video_caps = gst::Caps::builder("video/x-raw")
+                .field("width", source_config.width as i32)
+                .field("height", source_config.height as i32)
+                .field(
+                    "framerate",
+                    gst::Fraction::new(source_config.framerate as i32, 1),
+                )
+                .build();
+            let video_filter = make_element("capsfilter")?;
+            video_filter.set_property("caps", &video_caps);
+            let audio_source = make_element("audiotestsrc")?;
+            audio_source.set_property("is-live", true);
+            audio_source.set_property("freq", source_config.frequency);
+
+            pipeline.add_many([&video_source, &video_filter, &audio_source])?;
+            link_many(&[&video_source, &video_filter, video_converter])?;
+            link_many(&[&audio_source, audio_converter])?;
+        }
+        SourceKind::File => {
+            let path = source_config
+                .path
+                .as_ref()
+                .ok_or_else(|| Error::InvalidConfig("the file source needs a path".to_string()))?;
+            let file_source = make_element("filesrc")?;
+            file_source.set_property("location", path.display().to_string());
+            let decode_bin = make_element("decodebin")?;
+            // decoding runs as fast as it can, clocksync paces the file to real time
+            let audio_sync = make_element("clocksync")?;
+            let video_sync = make_element("clocksync")?;
+
+            pipeline.add_many([&file_source, &decode_bin, &audio_sync, &video_sync])?;
+            link_many(&[&file_source, &decode_bin])?;
+            link_many(&[&audio_sync, audio_converter])?;
+            link_many(&[&video_sync, video_converter])?;
+
+            decode_bin.connect_pad_added(move |_src, src_pad| {
+                println!("pad added to file decodebin");
+                let Some(new_pad_caps) = src_pad.current_caps() else {
+                    eprintln!("file decodebin pad has no caps");
+                    return;
+                };
+                let Some(new_pad_struct) = new_pad_caps.structure(0) else {
+                    return;
+                };
+                let new_pad_type = new_pad_struct.name();
+
+                let el: Option<&Element> = if new_pad_type.starts_with("audio/x-raw") {
+                    Some(&audio_sync)
+                } else if new_pad_type.starts_with("video/x-raw") {
+                    Some(&video_sync)
+                } else {
+                    None
+                };
+
+                if let Some(sync_sink_pad) = el.and_then(|el| el.static_pad("sink"))
+                    && !sync_sink_pad.is_linked()
+                    && let Err(err) = link_pads(src_pad, &sync_sink_pad)
+                {
+                    eprintln!("Error linking file decodebin pad: {}", err);
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// encodes the raw video with one codec, scaled down for a simulcast layer, into a new tee that
+// viewer branches can link to. returns the encoder, for bitrate changes, and the tee
+fn add_video_encoder(
+    pipeline: &Pipeline,
+    raw_video_tee: &Element,
+    codec: VideoCodec,
+    layer: Option<Layer>,
+    source_config: &SourceConfig,
+) -> Result<(Element, Element), Error> {
+    // each encoder runs on its own thread so a slow codec doesn't hold up the others
+    let queue = make_element("queue")?;
+    let encoder = make_video_encoder(codec)?;
+    let payloader = make_video_payloader(codec)?;
+    let twcc_filter = make_twcc_filter()?;
+    let video_tee = make_element("tee")?;
+
+    // keep encoding while nobody is watching
+    video_tee.set_property("allow-not-linked", true);
+
+    pipeline.add_many([&queue, &encoder, &payloader, &twcc_filter, &video_tee])?;
+    match layer {
+        Some(layer) => {
+            let (scaler, scaler_filter) =
+                make_layer_scaler(layer, source_config.width, source_config.height)?;
+            add_rid_extension(&payloader, layer.rid)?;
+            pipeline.add_many([&scaler, &scaler_filter])?;
+            link_many(&[&queue, &scaler, &scaler_filter, &encoder])?;
+        }
+        None => link_many(&[&queue, &encoder])?,
+    }
+    link_many(&[&encoder, &payloader, &twcc_filter, &video_tee])?;
+
+    let raw_tee_pad = raw_video_tee
+        .request_pad_simple("src_%u")
+        .ok_or_else(|| Error::Link("raw video tee: no free src pad".to_string()))?;
+    link_pads(
+        &raw_tee_pad,
+        &queue.static_pad("sink").expect("queue has a sink pad"),
+    )?;
+
+    Ok((encoder, video_tee))
+}
+
+// builds a webrtcbin for a new viewer and hooks its audio up to the running tee.
+// the encoders keep running, only the new branch is brought up to the pipeline's state
+fn add_viewer(
+    pipeline: &Pipeline,
+    audio_tee: &Element,
+    offered: Vec<VideoLayer>,
+    peer: PeerId,
+    ice: &IceConfig,
+    send_to_tokio: &Sender<Envelope>,
+) -> Result<ViewerBranch, Error> {
+    let webrtc_bin = make_element("webrtcbin")?;
+    webrtc_bin.set_property("name", format!("webrtcbin-{}", peer));
+    configure_ice(&webrtc_bin, ice);
+    let audio_queue = make_element("queue")?;
+
+    pipeline.add_many([&webrtc_bin, &audio_queue])?;
+
+    // audio first so every viewer's offer has the same m-line order
+    link_many(&[&audio_queue, &webrtc_bin])?;
+
+    // the video transceiver's codec-preferences put every offered codec and simulcast layer
+    // into the offer. nothing feeds it until the answer has picked one
+    let codecs = offered_codecs(&offered);
+    let mut codec_preferences = video_rtp_caps(&codecs, true);
+    // every codec is encoded at the same layers
+    let rids: Vec<&str> = offered
+        .iter()
+        .filter(|layer| layer.codec == codecs[0])
+        .filter_map(|layer| layer.rid)
+        .collect();
+    if !rids.is_empty() {
+        add_simulcast_offer(codec_preferences.make_mut(), &rids);
+    }
+    webrtc_bin.emit_by_name::<WebRTCRTPTransceiver>(
+        "add-transceiver",
+        &[&WebRTCRTPTransceiverDirection::Sendonly, &codec_preferences],
+    );
+
+    connect_webrtc_signals(&webrtc_bin, peer, send_to_tokio);
+    let connectivity = Arc::default();
+    watch_connectivity(&webrtc_bin, peer, &connectivity);
+
+    webrtc_bin.sync_state_with_parent()?;
+    audio_queue.sync_state_with_parent()?;
+
+    let audio_tee_pad = audio_tee
+        .request_pad_simple("src_%u")
+        .ok_or_else(|| Error::Link("audio tee: no free src pad".to_string()))?;
+    link_pads(
+        &audio_tee_pad,
+        &audio_queue
+            .static_pad("sink")
+            .expect("queue has a sink pad"),
+    )?;
+
+    Ok(ViewerBranch {
+        webrtc_bin,
+        audio_queue,
+        audio_tee_pad,
+        offered,
+        video: None,
+        stats: Arc::default(),
+        connectivity,
+    })
+}
+
+// each codec once, in the order they were offered
+fn offered_codecs(offered: &[VideoLayer]) -> Vec<VideoCodec> {
+    let mut codecs: Vec<VideoCodec> = Vec::new();
+    for layer in offered {
+        if !codecs.contains(&layer.codec) {
+            codecs.push(layer.codec);
+        }
+    }
+    codecs
+}
+
+// feeds the encoder of the codec and layer the viewer's answer picked into its video transceiver.
+// an answer that names no layer gets the codec's first, highest one
+fn link_video(
+    pipeline: &Pipeline,
+    branch: &mut ViewerBranch,
+    codec: VideoCodec,
+    rid: Option<&str>,
+) -> Result<VideoLayer, Error> {
+    let Some(layer) = branch.offered.iter().find(|layer| {
+        layer.codec == codec
+            && match (rid, layer.rid) {
+                (Some(wanted), Some(ours)) => wanted == ours,
+                _ => true,
+            }
+    }) else {
+        return Err(Error::InvalidSdp(format!(
+            "answer picked {:?} layer {:?}, which we did not offer",
+            codec, rid
+        )));
+    };
+    let layer = layer.clone();
+
+    let queue = make_element("queue")?;
+    pipeline.add(&queue)?;
+
+    // sink_1 belongs to the video transceiver, audio took sink_0
+    let webrtc_pad = branch
+        .webrtc_bin
+        .request_pad_simple("sink_1")
+        .ok_or_else(|| Error::Link("webrtcbin: no video sink pad".to_string()))?;
+    link_pads(
+        &queue.static_pad("src").expect("queue has a src pad"),
+        &webrtc_pad,
+    )?;
+    queue.sync_state_with_parent()?;
+
+    let tee_pad = layer
+        .tee
+        .request_pad_simple("src_%u")
+        .ok_or_else(|| Error::Link("video tee: no free src pad".to_string()))?;
+    link_pads(
+        &tee_pad,
+        &queue.static_pad("sink").expect("queue has a sink pad"),
+    )?;
+
+    branch.video = Some(VideoLink {
+        layer: layer.clone(),
+        tee_pad,
+        queue,
+    });
+    Ok(layer)
+}
+
+// asks a viewer's webrtcbin for its stats, the reply lands in branch.stats
+fn request_stats(branch: &ViewerBranch) {
+    let stats = branch.stats.clone();
+    let promise = Promise::with_change_func(move |reply| {
+        if let Ok(Some(report)) = reply {
+            *stats.lock().unwrap() = link_stats(report);
+        }
+    });
+    branch
+        .webrtc_bin
+        .emit_by_name::<()>("get-stats", &[&None::<Pad>, &promise]);
+}
+
+// updates a viewer's connectivity on every change of either state webrtcbin reports
+fn watch_connectivity(webrtc_bin: &Element, peer: PeerId, connectivity: &Arc<Mutex<Connectivity>>) {
+    for property in ["ice-connection-state", "connection-state"] {
+        let connectivity = connectivity.clone();
+        webrtc_bin.connect_notify(Some(property), move |webrtc_bin, _| {
+            let ice = webrtc_bin.property::<WebRTCICEConnectionState>("ice-connection-state");
+            let connection = webrtc_bin.property::<WebRTCPeerConnectionState>("connection-state");
+            let failed = ice == WebRTCICEConnectionState::Failed
+                || connection == WebRTCPeerConnectionState::Failed;
+            let lost = failed
+                || ice == WebRTCICEConnectionState::Disconnected
+                || connection == WebRTCPeerConnectionState::Disconnected;
+            let up = matches!(
+                ice,
+                WebRTCICEConnectionState::Connected | WebRTCICEConnectionState::Completed
+            );
+
+            let mut connectivity = connectivity.lock().unwrap();
+            connectivity.failed = failed;
+            if lost {
+                if connectivity.lost_since.is_none() {
+                    println!(
+                        "viewer {} lost connectivity: {:?}, {:?}.",
+                        peer, ice, connection
+                    );
+                    connectivity.lost_since = Some(Instant::now());
+                }
+            } else if up && connectivity.lost_since.take().is_some() {
+                println!("viewer {} is connected again.", peer);
+                connectivity.last_restart = None;
+            }
+        });
+    }
+}
+
+// ICE restart for a viewer whose link failed, or stayed disconnected past the grace period.
+// only the transport is renegotiated, the encoders and the viewer's branch keep running
+fn restart_ice_if_lost(peer: PeerId, branch: &ViewerBranch, send_to_tokio: &Sender<Envelope>) {
+    {
+        let mut connectivity = branch.connectivity.lock().unwrap();
+        let Some(lost_since) = connectivity.lost_since else {
+            return;
+        };
+        if !connectivity.failed && lost_since.elapsed() < DISCONNECTED_GRACE {
+            return;
+        }
+        if connectivity
+            .last_restart
+            .is_some_and(|restarted| restarted.elapsed() < ICE_RESTART_INTERVAL)
+        {
+            return;
+        }
+        // an offer still waiting for its answer would be replaced
+        let signaling = branch
+            .webrtc_bin
+            .property::<WebRTCSignalingState>("signaling-state");
+        if signaling != WebRTCSignalingState::Stable {
+            return;
+        }
+        connectivity.last_restart = Some(Instant::now());
+    }
+
+    println!("restarting ICE for viewer {}.", peer);
+    let options = gst::Structure::builder("offer-options")
+        .field("ice-restart", true)
+        .build();
+    send_offer(&branch.webrtc_bin, peer, send_to_tokio, Some(options));
+}
+
+// moves each encoder's bitrate towards what the worst of its viewers' links carries.
+// encoders nobody is watching, or whose viewers have no stats yet, keep their bitrate
+fn adapt_bitrates(video_encoders: &mut [VideoEncoder], viewers: &HashMap<PeerId, ViewerBranch>) {
+    for video_encoder in video_encoders {
+        let worst = viewers
+            .values()
+            .filter(|branch| {
+                branch
+                    .video
+                    .as_ref()
+                    .is_some_and(|video| video.layer == video_encoder.layer)
+            })
+            .filter_map(|branch| branch.stats.lock().unwrap().take())
+            .reduce(LinkStats::worst);
+        let Some(stats) = worst else {
+            continue;
+        };
+
+        if let Some(kbps) = video_encoder.bitrate.update(stats) {
+            println!(
+                "{:?} bitrate now {} kbit/s, viewers losing {:.1}% of packets.",
+                video_encoder.layer.name(),
+                kbps,
+                stats.loss * 100.0
+            );
+            set_video_bitrate(&video_encoder.encoder, video_encoder.layer.codec, kbps);
+        }
+    }
+}
+
+// detaches a viewer's branch from the tees and drops it, leaving the other viewers untouched
+fn remove_viewer(pipeline: &Pipeline, audio_tee: &Element, branch: ViewerBranch) {
+    audio_tee.release_request_pad(&branch.audio_tee_pad);
+
+    let mut elements = vec![&branch.webrtc_bin, &branch.audio_queue];
+    if let Some(video) = &branch.video {
+        video.layer.tee.release_request_pad(&video.tee_pad);
+        elements.push(&video.queue);
+    }
+
+    for element in &elements {
+        if let Err(err) = set_state(*element, State::Null) {
+            eprintln!("Error stopping viewer branch: {}", err);
+        }
+    }
+    if let Err(err) = pipeline.remove_many(elements) {
+        eprintln!("Error removing viewer branch: {}", err);
+    }
+}
+
+// has webrtcbin create an offer with the given options, sets it as the local description and
+// sends it to the viewer
+fn send_offer(
+    webrtc_bin: &Element,
+    peer: PeerId,
+    send_to_tokio: &Sender<Envelope>,
+    options: Option<gst::Structure>,
+) {
+    let webrtc_bin_clone = webrtc_bin.clone();
+    let sender_clone = send_to_tokio.clone();
+    let promise = Promise::with_change_func(move |res| {
+        let option = match res {
+            Ok(option) => option,
+            Err(err) => {
+                eprintln!("webrtcbin could not create an offer: {:?}", err);
+                return;
+            }
+        };
+
+        let Some(offer) = option.and_then(|val| {
+            val.get::<gstreamer_webrtc::WebRTCSessionDescription>("offer")
+                .ok()
+        }) else {
+            eprintln!("webrtcbin replied without an offer");
+            return;
+        };
+
+        println!(
+            "Got offer from webrtcbin, setting local description and sending Signal::Offer to tokio."
+        );
+        webrtc_bin_clone
+            .emit_by_name::<()>("set-local-description", &[&offer, &None::<gst::Promise>]);
+
+        let sdp = match offer.sdp().as_text() {
+            Ok(sdp) => sdp,
+            Err(err) => {
+                eprintln!("could not serialize offer: {}", err);
+                return;
+            }
+        };
+
+        let envelope = Envelope {
+            peer: Some(peer),
+            signal: Signal::Offer(sdp),
+        };
+        if sender_clone.blocking_send(envelope).is_err() {
+            eprintln!("{}, dropping offer", Error::ChannelClosed);
+            return;
+        }
+
+        println!("Sent to tokio.");
+    });
+
+    println!("Telling webrtcbin to create an offer");
+    webrtc_bin.emit_by_name::<()>("create-offer", &[&options, &promise]);
+}
+
+fn connect_webrtc_signals(webrtc_bin: &Element, peer: PeerId, send_to_tokio: &Sender<Envelope>) {
+    webrtc_bin.connect_notify(None, |x, y| {
+        println!("notify called");
+        println!("{:?}", y.name());
+        let sig = x.property::<gstreamer_webrtc::WebRTCSignalingState>("signaling-state");
+        let ice = x.property::<gstreamer_webrtc::WebRTCICEGatheringState>("ice-gathering-state");
+        println!("signaling-state: {:?}", sig);
+        println!("ice-gathering-state: {:?}", ice);
+    });
+
+    // the handler gets webrtcbin passed in, a clone of it here would keep webrtcbin alive forever
+    let sender_clone = send_to_tokio.clone();
+    webrtc_bin.connect("on-negotiation-needed", false, move |values| {
+        println!("Negotiation needed from webrtcbin for viewer {}", peer);
+        let webrtc_bin = values[0].get::<gst::Element>().expect("Invalid argument");
+        send_offer(&webrtc_bin, peer, &sender_clone, None);
+        None
+    });
+
+    let sender_clone = send_to_tokio.clone();
+    webrtc_bin.connect("on-ice-candidate", false, move |values| {
+        println!("on ice candidate event, sending to peer.");
+
+        let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
+        let mline_index = values[1].get::<u32>().expect("Invalid argument");
+        let candidate = values[2].get::<String>().expect("Invalid argument");
+
+        println!("mline_index: {}", mline_index);
+        println!("candidate: {}", candidate);
+
+        let envelope = Envelope {
+            peer: Some(peer),
+            signal: Signal::IceCandidate {
+                mline_index,
+                candidate,
+            },
+        };
+        if sender_clone.blocking_send(envelope).is_err() {
+            eprintln!("{}, dropping ICE candidate", Error::ChannelClosed);
+        }
+
+        None
+    });
 }
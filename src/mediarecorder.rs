@@ -0,0 +1,345 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use gst::prelude::*;
+use gst::{Bin, Bus, Element, GhostPad, Message, MessageType, MessageView, Pad, Pipeline, State};
+use gstreamer as gst;
+
+use crate::config::{RecordingConfig, RecordingFormat, VideoCodec};
+use crate::error::{Error, link_many, link_pads, make_element, set_state};
+use crate::mediacodecs::{codec_for_encoding_name, depayloader_name, parser_name};
+
+// records encoded RTP streams to disk without re-encoding them: each tee gets a branch that
+// depayloads and parses its stream into a splitmuxsink, which rotates the files.
+// recordings start and stop while the pipeline keeps playing
+
+// how long shutdown waits for the muxers to write out their indexes
+const FINALIZE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// recording bins are named recording-<n>, which is how their bus messages are recognised
+const BIN_PREFIX: &str = "recording-";
+
+// mp4mux writes a fragment this often, in milliseconds
+const MP4_FRAGMENT_DURATION: u32 = 1000;
+
+// what an RTP stream to be recorded carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecordedMedia {
+    Video(VideoCodec),
+    Opus,
+}
+
+// what an application/x-rtp stream carries, None for media we can't record
+pub(crate) fn recorded_media(caps: &gst::CapsRef) -> Option<RecordedMedia> {
+    let encoding_name = caps.structure(0)?.get::<&str>("encoding-name").ok()?;
+    if encoding_name.eq_ignore_ascii_case("OPUS") {
+        return Some(RecordedMedia::Opus);
+    }
+    codec_for_encoding_name(encoding_name).map(RecordedMedia::Video)
+}
+
+// one recording's elements, in a bin of their own so they can come and go while the live
+// stream plays on
+struct Recording {
+    bin: Bin,
+    // the tee pads feeding it, released when it stops
+    tee_pads: Vec<(Element, Pad)>,
+}
+
+pub(crate) struct Recorder {
+    config: RecordingConfig,
+    // producer or consumer, the start of each file name
+    file_prefix: &'static str,
+    streams: Vec<(Element, RecordedMedia)>,
+    active: Option<Recording>,
+    // stopped, waiting for their muxers to finish the file
+    finishing: Vec<Recording>,
+    started: u32,
+}
+
+impl Recorder {
+    pub(crate) fn new(config: &RecordingConfig, file_prefix: &'static str) -> Self {
+        Recorder {
+            config: config.clone(),
+            file_prefix,
+            streams: Vec::new(),
+            active: None,
+            finishing: Vec::new(),
+            started: 0,
+        }
+    }
+
+    // a tee carrying one of the RTP streams to record, picked up by the next recording started
+    pub(crate) fn add_stream(&mut self, tee: Element, media: RecordedMedia) {
+        self.streams.push((tee, media));
+    }
+
+    // forgets the streams of a session that has ended, finalizing any recording of them
+    pub(crate) fn clear_streams(&mut self) {
+        self.stop();
+        self.streams.clear();
+    }
+
+    pub(crate) fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub(crate) fn start(&mut self, pipeline: &Pipeline) -> Result<(), Error> {
+        if self.active.is_some() {
+            return Ok(());
+        }
+        if self.streams.is_empty() {
+            return Err(Error::Recording("no streams to record yet".to_string()));
+        }
+
+        self.started += 1;
+        let location = recording_location(&self.config, self.file_prefix, self.started);
+        self.active = Some(self.build_recording(pipeline, &location)?);
+        println!("recording to {}.", location);
+        Ok(())
+    }
+
+    // detaches the active recording and pushes EOS through it so the muxer writes its index.
+    // its bin is removed once the EOS comes back on the bus
+    pub(crate) fn stop(&mut self) {
+        let Some(recording) = self.active.take() else {
+            return;
+        };
+
+        println!("stopping recording, finalizing the file.");
+        for (tee, tee_pad) in &recording.tee_pads {
+            if let Some(peer) = tee_pad.peer() {
+                let _ = tee_pad.unlink(&peer);
+                peer.send_event(gst::event::Eos::new());
+            }
+            tee.release_request_pad(tee_pad);
+        }
+        self.finishing.push(recording);
+    }
+
+    // deals with the bus messages about recordings, returning whether this was one.
+    // a finished file or a failed recording gets its bin removed, the live stream carries on
+    pub(crate) fn handle_message(&mut self, msg: &Message) -> bool {
+        let Some(name) = msg.src().and_then(recording_name) else {
+            return false;
+        };
+
+        match msg.view() {
+            MessageView::Error(err) => {
+                eprintln!("recording {} failed: {}", name, err.error());
+                if self
+                    .active
+                    .as_ref()
+                    .is_some_and(|recording| recording.bin.name() == name)
+                {
+                    // nothing more will reach the file, release the tees as a normal stop would
+                    self.stop();
+                }
+                self.remove(&name);
+                true
+            }
+            MessageView::Element(element) => {
+                let forwarded_eos = element
+                    .structure()
+                    .filter(|structure| structure.name() == "GstBinForwarded")
+                    .and_then(|structure| structure.get::<Message>("message").ok())
+                    .is_some_and(|forwarded| forwarded.type_() == MessageType::Eos);
+                if forwarded_eos {
+                    println!("recording {} finalized.", name);
+                    // EOS from the sources, as on shutdown, reaches the active recording too
+                    if self
+                        .active
+                        .as_ref()
+                        .is_some_and(|recording| recording.bin.name() == name)
+                    {
+                        self.stop();
+                    }
+                    self.remove(&name);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // stops recording and waits for every file to be finalized, before the pipeline is stopped.
+    // the main loop has already quit, so the messages are taken straight off the bus
+    pub(crate) fn finish(&mut self, bus: &Bus) {
+        self.stop();
+
+        let deadline = Instant::now() + FINALIZE_TIMEOUT;
+        while !self.finishing.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(msg) = bus.timed_pop_filtered(
+                gst::ClockTime::from_nseconds(remaining.as_nanos() as u64),
+                &[MessageType::Element, MessageType::Error],
+            ) else {
+                eprintln!(
+                    "gave up waiting for {} recording(s) to finalize.",
+                    self.finishing.len()
+                );
+                return;
+            };
+            self.handle_message(&msg);
+        }
+    }
+
+    fn build_recording(&self, pipeline: &Pipeline, location: &str) -> Result<Recording, Error> {
+        std::fs::create_dir_all(&self.config.directory).map_err(|err| {
+            Error::Recording(format!("{}: {}", self.config.directory.display(), err))
+        })?;
+
+        let bin = Bin::with_name(&format!("{}{}", BIN_PREFIX, self.started));
+        // the muxer's EOS is otherwise swallowed by the bin, it is how we know the file is done
+        bin.set_property("message-forward", true);
+
+        let splitmux = make_element("splitmuxsink")?;
+        splitmux.set_property("muxer", make_muxer(self.config.format)?);
+        splitmux.set_property("location", location);
+        if let Some(seconds) = self.config.max_file_duration {
+            splitmux.set_property("max-size-time", seconds * 1_000_000_000);
+            // x264enc uses intra refresh and never emits an IDR frame on its own, so a split
+            // point has to be asked for. splitmuxsink only asks while there is no size limit.
+            splitmux.set_property("send-keyframe-requests", true);
+        }
+        if let Some(megabytes) = self.config.max_file_size {
+            splitmux.set_property("max-size-bytes", megabytes * 1_000_000);
+        }
+        bin.add(&splitmux)?;
+
+        let mut inputs: Vec<(Element, GhostPad, RecordedMedia)> = Vec::new();
+        for (tee, media) in &self.streams {
+            let input = add_stream_branch(&bin, &splitmux, *media)?;
+            inputs.push((tee.clone(), input, *media));
+        }
+
+        pipeline.add(&bin)?;
+        bin.sync_state_with_parent()?;
+
+        let mut tee_pads = Vec::new();
+        for (tee, input, media) in inputs {
+            let tee_pad = tee
+                .request_pad_simple("src_%u")
+                .ok_or_else(|| Error::Link("tee: no free src pad".to_string()))?;
+            link_pads(&tee_pad, input.upcast_ref::<Pad>())?;
+            tee_pads.push((tee, tee_pad));
+
+            // a file has to start on a keyframe, rather than wait for the next one ask for it
+            if let RecordedMedia::Video(_) = media {
+                let force_key_unit = gst::Structure::builder("GstForceKeyUnit")
+                    .field("all-headers", true)
+                    .build();
+                input.push_event(gst::event::CustomUpstream::new(force_key_unit));
+            }
+        }
+
+        Ok(Recording { bin, tee_pads })
+    }
+
+    // takes a stopped or failed recording's bin out of the pipeline
+    fn remove(&mut self, name: &str) {
+        let Some(idx) = self
+            .finishing
+            .iter()
+            .position(|recording| recording.bin.name() == name)
+        else {
+            return;
+        };
+        let recording = self.finishing.remove(idx);
+
+        if let Err(err) = set_state(&recording.bin, State::Null) {
+            eprintln!("Error stopping recording: {}", err);
+        }
+        if let Some(parent) = recording.bin.parent().and_downcast::<Bin>()
+            && let Err(err) = parent.remove(&recording.bin)
+        {
+            eprintln!("Error removing recording: {}", err);
+        }
+    }
+}
+
+// queue -> depayloader -> parser into one of splitmuxsink's inputs, returning the bin's
+// ghost pad for it
+fn add_stream_branch(
+    bin: &Bin,
+    splitmux: &Element,
+    media: RecordedMedia,
+) -> Result<GhostPad, Error> {
+    let queue = make_element("queue")?;
+    let (depayloader, parser, splitmux_pad) = match media {
+        RecordedMedia::Video(codec) => (
+            make_element(depayloader_name(codec))?,
+            parser_name(codec).map(make_element).transpose()?,
+            "video",
+        ),
+        RecordedMedia::Opus => (
+            make_element("rtpopusdepay")?,
+            Some(make_element("opusparse")?),
+            "audio_%u",
+        ),
+    };
+
+    bin.add_many([&queue, &depayloader])?;
+    link_many(&[&queue, &depayloader])?;
+    let last = match &parser {
+        Some(parser) => {
+            bin.add(parser)?;
+            link_many(&[&depayloader, parser])?;
+            parser
+        }
+        None => &depayloader,
+    };
+
+    let sink_pad = splitmux
+        .request_pad_simple(splitmux_pad)
+        .ok_or_else(|| Error::Link(format!("splitmuxsink: no {} pad", splitmux_pad)))?;
+    link_pads(
+        &last.static_pad("src").expect("parsers have a src pad"),
+        &sink_pad,
+    )?;
+
+    let input = GhostPad::with_target(&queue.static_pad("sink").expect("queue has a sink pad"))?;
+    bin.add_pad(&input)?;
+    Ok(input)
+}
+
+fn make_muxer(format: RecordingFormat) -> Result<Element, Error> {
+    match format {
+        RecordingFormat::Mp4 => {
+            let muxer = make_element("mp4mux")?;
+            muxer.set_property("fragment-duration", MP4_FRAGMENT_DURATION);
+            Ok(muxer)
+        }
+        RecordingFormat::Mkv => make_element("matroskamux"),
+    }
+}
+
+// <directory>/<prefix>-<unix time>-<n>-%05d.<ext>, splitmuxsink fills in the file number
+fn recording_location(config: &RecordingConfig, file_prefix: &str, started: u32) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    config
+        .directory
+        .join(format!(
+            "{}-{}-{}-%05d.{}",
+            file_prefix,
+            now,
+            started,
+            config.format.extension()
+        ))
+        .to_string_lossy()
+        .into_owned()
+}
+
+// the recording bin an element belongs to, if any
+fn recording_name(object: &gst::Object) -> Option<String> {
+    let mut current = Some(object.clone());
+    while let Some(object) = current {
+        if object.is::<Bin>() && object.name().starts_with(BIN_PREFIX) {
+            return Some(object.name().to_string());
+        }
+        current = object.parent();
+    }
+    None
+}
@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use gst::Pipeline;
+use gst::glib::{ControlFlow, MainContext, MainLoop, Priority, source};
+use gst::prelude::*;
+use gstreamer as gst;
+use tokio::sync::mpsc::Sender;
+
+use crate::error::Error;
+use crate::{Envelope, PeerId, Signal};
+
+// how long a shutdown waits for EOS to reach every sink before quitting anyway
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+
+// tells to, or the whole room when None, that we are leaving. runs in the signal task on the
+// main context, which must not block on a full channel
+pub(crate) async fn say_bye(send_to_tokio: &Sender<Envelope>, to: Option<PeerId>) {
+    let bye = Envelope {
+        peer: to,
+        signal: Signal::Bye,
+    };
+    if send_to_tokio.send(bye).await.is_err() {
+        eprintln!("{}, leaving without saying bye", Error::ChannelClosed);
+    }
+}
+
+// sends EOS from the sources so every branch drains and the muxers finish their files. the
+// bus's EOS quits the main loop, but sinks that never got linked don't post one, so the main
+// loop is quit after DRAIN_TIMEOUT regardless
+pub(crate) fn begin_shutdown(pipeline: &Pipeline, main_loop: &MainLoop, context: &MainContext) {
+    println!("shutting down. draining the pipeline.");
+    if !pipeline.send_event(gst::event::Eos::new()) {
+        eprintln!("pipeline did not take EOS, stopping without draining.");
+        main_loop.quit();
+        return;
+    }
+
+    let main_loop = main_loop.clone();
+    source::timeout_source_new(DRAIN_TIMEOUT, None, Priority::DEFAULT, move || {
+        eprintln!("gave up waiting for EOS after {:?}.", DRAIN_TIMEOUT);
+        main_loop.quit();
+        ControlFlow::Break
+    })
+    .attach(Some(context));
+}
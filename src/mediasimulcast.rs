@@ -0,0 +1,137 @@
+use gst::Element;
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_rtp::RTPHeaderExtension;
+use gstreamer_rtp::prelude::*;
+use gstreamer_webrtc::gst_sdp::SDPMessageRef;
+
+use crate::error::{Error, make_element};
+use crate::mediacodecs::sdp_error;
+
+// simulcast: the producer encodes the video at several resolutions and offers them as
+// RID-tagged layers of one send transceiver. each viewer's answer names the layer it wants
+
+// one simulcast layer, its resolution is the source's divided by scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Layer {
+    pub(crate) rid: &'static str,
+    pub(crate) scale: u32,
+}
+
+// highest first, viewers that don't ask for a layer get the first one
+pub(crate) const LAYERS: [Layer; 3] = [
+    Layer { rid: "h", scale: 1 },
+    Layer { rid: "m", scale: 2 },
+    Layer { rid: "l", scale: 4 },
+];
+
+// tags every packet with its layer's RID so the viewer can tell the layers apart
+const RTP_STREAM_ID_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id";
+// TWCC has 1
+const RTP_STREAM_ID_EXTENSION_ID: u32 = 2;
+
+// videoscale and the capsfilter it scales to, for the raw video going into a layer's encoder
+pub(crate) fn make_layer_scaler(
+    layer: Layer,
+    width: u32,
+    height: u32,
+) -> Result<(Element, Element), Error> {
+    let scaler = make_element("videoscale")?;
+    let filter = make_element("capsfilter")?;
+    // encoders want even dimensions
+    let caps = gst::Caps::builder("video/x-raw")
+        .field("width", ((width / layer.scale) & !1) as i32)
+        .field("height", ((height / layer.scale) & !1) as i32)
+        .build();
+    filter.set_property("caps", &caps);
+    Ok((scaler, filter))
+}
+
+pub(crate) fn add_rid_extension(payloader: &Element, rid: &str) -> Result<(), Error> {
+    let extension = RTPHeaderExtension::create_from_uri(RTP_STREAM_ID_URI).ok_or_else(|| {
+        Error::MissingElement {
+            factory: "rtphdrextstreamid".to_string(),
+        }
+    })?;
+    extension.set_id(RTP_STREAM_ID_EXTENSION_ID);
+    extension.set_property("rid", rid);
+    payloader.emit_by_name::<()>("add-extension", &[&extension]);
+    Ok(())
+}
+
+// the a=rid and a=simulcast lines of an offer, for every codec of a transceiver's
+// codec-preferences
+pub(crate) fn add_simulcast_offer(caps: &mut gst::CapsRef, rids: &[&str]) {
+    for structure in caps.iter_mut() {
+        structure.set(
+            format!("extmap-{}", RTP_STREAM_ID_EXTENSION_ID),
+            RTP_STREAM_ID_URI,
+        );
+        for rid in rids {
+            structure.set(format!("rid-{}", rid), "send");
+        }
+        structure.set("a-simulcast", format!("send {}", rids.join(";")));
+    }
+}
+
+// the layers an offer's video m-line sends, from its "a=rid:h send" lines
+pub(crate) fn offered_layers(sdp: &SDPMessageRef) -> Vec<String> {
+    let Some(media) = (0..sdp.medias_len())
+        .filter_map(|idx| sdp.media(idx))
+        .find(|media| media.media() == Some("video"))
+    else {
+        return Vec::new();
+    };
+
+    (0..media.attributes_len())
+        .filter_map(|idx| media.attribute(idx))
+        .filter(|attribute| attribute.key() == "rid")
+        .filter_map(|attribute| {
+            let (rid, direction) = attribute.value()?.split_once(' ')?;
+            direction.starts_with("send").then(|| rid.to_string())
+        })
+        .collect()
+}
+
+// the layer an answer asked for, the first one its "a=simulcast:recv" line lists that isn't paused
+pub(crate) fn answered_layer(sdp: &SDPMessageRef) -> Option<String> {
+    let media = (0..sdp.medias_len())
+        .filter_map(|idx| sdp.media(idx))
+        .find(|media| media.media() == Some("video"))?;
+    let simulcast = media.attribute_val("simulcast")?;
+    let streams = simulcast.strip_prefix("recv ")?;
+
+    streams
+        .split([';', ','])
+        .find(|rid| !rid.starts_with('~'))
+        .map(|rid| rid.trim().to_string())
+}
+
+// SDP munging for the answer: receive only the one layer we want, whatever webrtcbin answered
+pub(crate) fn receive_only_layer(sdp: &mut SDPMessageRef, rid: &str) -> Result<(), Error> {
+    for media_idx in 0..sdp.medias_len() {
+        let Some(media) = sdp.media_mut(media_idx) else {
+            continue;
+        };
+        if media.media() != Some("video") {
+            continue;
+        }
+
+        for idx in (0..media.attributes_len()).rev() {
+            let simulcast_line = media
+                .attribute(idx)
+                .is_some_and(|attribute| matches!(attribute.key(), "rid" | "simulcast"));
+            if simulcast_line {
+                media.remove_attribute(idx).map_err(sdp_error)?;
+            }
+        }
+        media
+            .add_attribute("rid", Some(&format!("{} recv", rid)))
+            .map_err(sdp_error)?;
+        media
+            .add_attribute("simulcast", Some(&format!("recv {}", rid)))
+            .map_err(sdp_error)?;
+    }
+
+    Ok(())
+}
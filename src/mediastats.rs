@@ -0,0 +1,41 @@
+use gstreamer as gst;
+
+use crate::bitrate::LinkStats;
+
+// reads a viewer's link quality out of a webrtcbin get-stats report.
+// the report has one structure per stats object, identified by which fields it carries
+
+// a link's transport-wide congestion control figures, present once the viewer sends TWCC feedback
+const TWCC_STATS: &str = "gst-twcc-stats";
+
+pub(crate) fn link_stats(report: &gst::StructureRef) -> Option<LinkStats> {
+    let mut receiver_reports: Option<LinkStats> = None;
+
+    for (_, value) in report.iter() {
+        let Ok(stats) = value.get::<gst::Structure>() else {
+            continue;
+        };
+
+        // TWCC covers every packet rather than a receiver report's sample, so it wins
+        if let Ok(twcc) = stats.get::<gst::Structure>(TWCC_STATS) {
+            return Some(LinkStats {
+                loss: twcc.get::<f64>("packet-loss-pct").unwrap_or(0.0) / 100.0,
+                received_kbps: twcc.get::<u32>("bitrate-recv").ok().map(|bps| bps / 1000),
+            });
+        }
+
+        // remote-inbound-rtp, what the viewer's RTCP receiver reports say about one stream
+        if let Ok(fraction_lost) = stats.get::<f64>("fraction-lost") {
+            let stream = LinkStats {
+                loss: fraction_lost,
+                received_kbps: None,
+            };
+            receiver_reports = Some(match receiver_reports {
+                Some(others) => others.worst(stream),
+                None => stream,
+            });
+        }
+    }
+
+    receiver_reports
+}
@@ -1,61 +1,398 @@
+use std::{collections::VecDeque, time::Duration};
+
+use futures_util::StreamExt;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
     net::TcpStream,
     sync::mpsc::{Receiver, Sender},
 };
+use tokio_tungstenite::WebSocketStream;
+
+use crate::{
+    ClientMessage, Envelope, PeerId, RelayMessage, Signal,
+    codec::{
+        self, CodecError, DEFAULT_MAX_LINE_LENGTH, FrameReader, FrameWriter, ReadFrames,
+        WriteFrames,
+    },
+    config::{Config, ReconnectConfig, Transport},
+    tls::{RelayStream, TlsClient},
+    websocket::{WsFrameReader, WsFrameWriter},
+};
+
+// how long a closing connection waits for the relay to close its side
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// how many outgoing messages are kept while the relay is unreachable, the oldest go first
+const MAX_PENDING: usize = 256;
+
+// a serialized ClientMessage waiting to be written to the relay
+struct PendingMessage {
+    // the peer it is addressed to, None for the whole room
+    to: Option<PeerId>,
+    line: String,
+}
+
+// how run_peer_socket retries when the relay is unreachable or drops the connection
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    // fraction of each delay that is randomised so clients don't reconnect in lockstep
+    pub jitter: f64,
+    // None keeps retrying forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy::from(&ReconnectConfig::default())
+    }
+}
+
+impl From<&ReconnectConfig> for ReconnectPolicy {
+    fn from(config: &ReconnectConfig) -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(config.initial_delay_ms),
+            max_delay: Duration::from_millis(config.max_delay_ms),
+            multiplier: config.multiplier,
+            jitter: config.jitter,
+            max_attempts: config.max_attempts,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    // delay before the given retry, attempts start at 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        let base = base.min(self.max_delay.as_secs_f64());
+
+        // random factor in [1 - jitter, 1 + jitter)
+        let factor = 1.0 + self.jitter * (rand::random::<f64>() * 2.0 - 1.0);
+
+        Duration::from_secs_f64((base * factor).max(0.0))
+    }
+}
+
+// why a single relay connection ended
+enum Disconnect {
+    RelayClosed,
+    PipelineClosed,
+    // reconnecting with the same token would only be refused again
+    Rejected(String),
+}
+
+// an established link to the relay, in whichever transport the config asks for
+enum RelayConnection {
+    Tcp(Box<dyn RelayStream>),
+    WebSocket(Box<WebSocketStream<Box<dyn RelayStream>>>),
+}
 
-use crate::{HOST, Signal};
+async fn connect(config: &Config, tls: Option<&TlsClient>) -> Result<RelayConnection, CodecError> {
+    let tcp_stream = TcpStream::connect(&config.relay_address).await?;
+    let stream: Box<dyn RelayStream> = match tls {
+        Some(tls) => Box::new(tls.connect(tcp_stream).await?),
+        None => Box::new(tcp_stream),
+    };
 
+    match config.transport {
+        Transport::Tcp => Ok(RelayConnection::Tcp(stream)),
+        Transport::WebSocket => {
+            let scheme = if tls.is_some() { "wss" } else { "ws" };
+            let url = format!("{}://{}/", scheme, config.relay_address);
+            let (ws_stream, _response) = tokio_tungstenite::client_async(url, stream).await?;
+            Ok(RelayConnection::WebSocket(Box::new(ws_stream)))
+        }
+    }
+}
+
+// keeps a connection to the relay alive for as long as the pipeline is running.
+// outgoing signals are queued while disconnected, and the pipeline gets a
+// Signal::Reconnected once a dropped link has been re-established
 pub async fn run_peer_socket(
-    send_to_gst: Sender<Signal>,
-    mut tokio_recv: Receiver<Signal>,
+    config: &Config,
+    policy: &ReconnectPolicy,
+    send_to_gst: Sender<Envelope>,
+    mut tokio_recv: Receiver<Envelope>,
 ) -> std::io::Result<()> {
-    let relay_address = format!("{}:8080", HOST);
-    let mut tcp_stream = TcpStream::connect(relay_address).await?;
+    let relay_address = &config.relay_address;
+    let mut pending: VecDeque<PendingMessage> = VecDeque::new();
+    let mut connected_before = false;
+    let mut attempt: u32 = 0;
+    // join the room before any signaling so the relay only forwards our messages to its members
+    let join = ClientMessage::Join {
+        room: config.room.clone(),
+        token: config.token.clone(),
+    };
+
+    let tls = if config.tls.is_enabled() {
+        let tls = TlsClient::new(relay_address, config.tls.ca_file.as_deref())
+            .map_err(std::io::Error::other)?;
+        Some(tls)
+    } else {
+        None
+    };
 
-    let (read_half, write_half) = tcp_stream.split();
-    let mut socket_reader = BufReader::new(read_half);
-    let mut socket_writer = BufWriter::new(write_half);
+    loop {
+        match connect(config, tls.as_ref()).await {
+            Ok(connection) => {
+                println!("Connected to relay at {}.", relay_address);
+                attempt = 0;
+
+                let result = run_connection(
+                    connection,
+                    &join,
+                    connected_before,
+                    &send_to_gst,
+                    &mut tokio_recv,
+                    &mut pending,
+                )
+                .await;
+                connected_before = true;
+
+                match result {
+                    Ok(Disconnect::PipelineClosed) => return Ok(()),
+                    Ok(Disconnect::RelayClosed) => eprintln!("Relay closed the connection."),
+                    Ok(Disconnect::Rejected(reason)) => {
+                        return Err(std::io::Error::other(format!(
+                            "relay rejected the connection: {}",
+                            reason
+                        )));
+                    }
+                    Err(err) => eprintln!("Relay connection failed: {}", err),
+                }
+            }
+            Err(err) => {
+                eprintln!("Failed to connect to relay at {}: {}", relay_address, err);
+            }
+        }
+
+        attempt += 1;
+        if policy.max_attempts.is_some_and(|max| attempt > max) {
+            return Err(std::io::Error::other(format!(
+                "gave up reconnecting to relay after {} attempts",
+                attempt - 1
+            )));
+        }
 
-    let mut socket_buffer = String::new();
+        let delay = policy.delay(attempt);
+        println!(
+            "Reconnecting to relay in {:?} (attempt {}).",
+            delay, attempt
+        );
+
+        // keep queueing what gst sends while we wait
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => break,
+                msg_result = tokio_recv.recv() => match msg_result {
+                    Some(envelope) => queue_envelope(&mut pending, envelope),
+                    None => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+async fn run_connection(
+    connection: RelayConnection,
+    join: &ClientMessage,
+    reconnected: bool,
+    send_to_gst: &Sender<Envelope>,
+    tokio_recv: &mut Receiver<Envelope>,
+    pending: &mut VecDeque<PendingMessage>,
+) -> Result<Disconnect, CodecError> {
+    match connection {
+        RelayConnection::Tcp(stream) => {
+            let (read_half, write_half) = tokio::io::split(stream);
+            let socket_reader = FrameReader::new(read_half, DEFAULT_MAX_LINE_LENGTH);
+            let socket_writer = FrameWriter::new(write_half);
+            exchange_signals(
+                socket_reader,
+                socket_writer,
+                join,
+                reconnected,
+                send_to_gst,
+                tokio_recv,
+                pending,
+            )
+            .await
+        }
+        RelayConnection::WebSocket(ws_stream) => {
+            let (sink, stream) = (*ws_stream).split();
+            let socket_reader = WsFrameReader::new(stream, DEFAULT_MAX_LINE_LENGTH);
+            let socket_writer = WsFrameWriter::new(sink);
+            exchange_signals(
+                socket_reader,
+                socket_writer,
+                join,
+                reconnected,
+                send_to_gst,
+                tokio_recv,
+                pending,
+            )
+            .await
+        }
+    }
+}
+
+async fn exchange_signals<R: ReadFrames, W: WriteFrames>(
+    mut socket_reader: R,
+    mut socket_writer: W,
+    join: &ClientMessage,
+    reconnected: bool,
+    send_to_gst: &Sender<Envelope>,
+    tokio_recv: &mut Receiver<Envelope>,
+    pending: &mut VecDeque<PendingMessage>,
+) -> Result<Disconnect, CodecError> {
+    socket_writer.write_frame(join).await?;
+
+    // peer ids from the old connection are gone, only what is for the whole room still applies
+    if reconnected {
+        let queued = pending.len();
+        pending.retain(|msg| msg.to.is_none());
+        if pending.len() < queued {
+            eprintln!(
+                "Discarded {} queued signal(s) for peers of the old connection.",
+                queued - pending.len()
+            );
+        }
+    }
+
+    // send whatever piled up while we were disconnected
+    flush_pending(&mut socket_writer, pending).await?;
+
+    if reconnected {
+        let envelope = Envelope {
+            peer: None,
+            signal: Signal::Reconnected,
+        };
+        if send_to_gst.send(envelope).await.is_err() {
+            return Ok(Disconnect::PipelineClosed);
+        }
+    }
 
     loop {
         tokio::select! {
-            // read from socket, deserialize to Signal, send to gst if valid
-            _ = socket_reader.read_line(&mut socket_buffer) => {
-                let msg: Option<Signal> = match serde_json::from_str(&socket_buffer) {
-                    Ok(m) => Some(m),
+            // read a frame from socket, send delivered Signals to gst
+            read_result = socket_reader.read_frame::<RelayMessage>() => {
+                let msg = match read_result {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => return Ok(Disconnect::RelayClosed),
+                    Err(err) if err.is_fatal() => return Err(err),
                     Err(err) => {
-                        eprintln!("Bad message incoming: {:?}", err );
-                        None
+                        eprintln!("Bad message incoming: {}", err);
+                        continue;
                     }
                 };
 
-                if let Some(msg) = msg {
-                    send_to_gst.send(msg).await.unwrap();
-                    socket_buffer.clear();
+                let envelope = match msg {
+                    RelayMessage::Welcome { peer_id } => {
+                        println!("Relay assigned us peer id {}.", peer_id);
+                        continue;
+                    }
+                    // local signals such as Reconnected never decode, so no peer can fake them
+                    RelayMessage::Deliver { from, signal } => Envelope {
+                        peer: Some(from),
+                        signal,
+                    },
+                    RelayMessage::PeerJoined { peer_id } => Envelope {
+                        peer: Some(peer_id),
+                        signal: Signal::PeerJoined,
+                    },
+                    RelayMessage::PeerLeft { peer_id } => Envelope {
+                        peer: Some(peer_id),
+                        signal: Signal::PeerLeft,
+                    },
+                    RelayMessage::Rejected { reason } => return Ok(Disconnect::Rejected(reason)),
+                    RelayMessage::ShuttingDown => {
+                        // it may be restarting, reconnect as after any other drop
+                        println!("Relay is shutting down.");
+                        return Ok(Disconnect::RelayClosed);
+                    }
+                };
+
+                // the pipeline has stopped, there is nobody left to signal for
+                if send_to_gst.send(envelope).await.is_err() {
+                    return Ok(Disconnect::PipelineClosed);
                 }
             }
 
-            // read from gst, serialize from Signal, send out over socket if valid
+            // read from gst, queue it and send out over socket
             msg_result = tokio_recv.recv() => {
-                if let Some(signal) = msg_result {
-                    let msg: Option<String> = match serde_json::to_string(&signal) {
-                        Ok(m) => Some(m),
-                        Err(err) => {
-                            eprintln!("Bad message outgoing: {:?}", err );
-                            None
-                        }
-                    };
-
-
-                    if let Some(msg) = msg {
-                        let s = msg + "\n";
-                        socket_writer.write_all(s.as_bytes()).await?;
-                        socket_writer.flush().await?;
+                match msg_result {
+                    Some(envelope) => {
+                        queue_envelope(pending, envelope);
+                        flush_pending(&mut socket_writer, pending).await?;
+                    }
+                    None => {
+                        close_connection(&mut socket_reader, &mut socket_writer).await?;
+                        return Ok(Disconnect::PipelineClosed);
                     }
                 }
             }
         }
     }
 }
+
+// closes our side once everything queued is written, then reads until the relay closes its side.
+// dropping the socket with unread data would reset it, possibly before the relay read our Bye
+async fn close_connection<R: ReadFrames, W: WriteFrames>(
+    socket_reader: &mut R,
+    socket_writer: &mut W,
+) -> Result<(), CodecError> {
+    socket_writer.shutdown().await?;
+
+    // whatever still arrives is for a pipeline that has stopped
+    let drain = async {
+        loop {
+            match socket_reader.read_frame::<RelayMessage>().await {
+                Ok(Some(_)) => continue,
+                Err(err) if !err.is_fatal() => continue,
+                Ok(None) | Err(_) => return,
+            }
+        }
+    };
+    if tokio::time::timeout(CLOSE_TIMEOUT, drain).await.is_err() {
+        eprintln!("Relay did not close the connection, closing it anyway.");
+    }
+    Ok(())
+}
+
+// serialize from Signal into an addressed ClientMessage, queue it if valid
+fn queue_envelope(pending: &mut VecDeque<PendingMessage>, envelope: Envelope) {
+    let to = envelope.peer;
+    let msg = ClientMessage::Send {
+        to,
+        signal: envelope.signal,
+    };
+
+    let line = match codec::encode(&msg) {
+        Ok(line) => line,
+        Err(err) => {
+            eprintln!("Bad message outgoing: {}", err);
+            return;
+        }
+    };
+    if pending.len() >= MAX_PENDING {
+        pending.pop_front();
+        eprintln!("Outgoing queue is full, dropped the oldest signal.");
+    }
+    pending.push_back(PendingMessage { to, line });
+}
+
+// writes queued lines in order, each one stays queued until it has been written
+async fn flush_pending<W: WriteFrames>(
+    socket_writer: &mut W,
+    pending: &mut VecDeque<PendingMessage>,
+) -> Result<(), CodecError> {
+    while let Some(msg) = pending.front() {
+        socket_writer.write_encoded(&msg.line).await?;
+        pending.pop_front();
+    }
+
+    Ok(())
+}
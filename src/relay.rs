@@ -0,0 +1,642 @@
+use futures_util::StreamExt;
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, Sender, error::TrySendError},
+        watch,
+    },
+    task::JoinSet,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    ClientMessage, PeerId, RelayMessage, Signal,
+    auth::{self, Role},
+    codec::{
+        self, CodecError, DEFAULT_MAX_LINE_LENGTH, FrameReader, FrameWriter, ReadFrames,
+        WriteFrames,
+    },
+    tls::RelayStream,
+    websocket::{WsFrameReader, WsFrameWriter},
+};
+
+// the room's members, each with the queue of encoded messages waiting to be written to it
+struct Room {
+    members: HashMap<PeerId, Sender<String>>,
+}
+
+impl Room {
+    // queues payload for to, or for every member but from when to is None. a member whose queue
+    // is full has fallen too far behind to catch up, it is dropped from the room and disconnected
+    fn deliver(&mut self, from: PeerId, to: Option<PeerId>, payload: &str) {
+        let mut fallen_behind = Vec::new();
+        for (&peer_id, queue) in &self.members {
+            if peer_id == from || to.is_some_and(|to| to != peer_id) {
+                continue;
+            }
+            match queue.try_send(payload.to_string()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => fallen_behind.push(peer_id),
+                // its connection is closing, it leaves the room on its own
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+
+        for peer_id in fallen_behind {
+            eprintln!("Peer {} fell behind, disconnecting it.", peer_id);
+            // dropping its queue's sender ends the connection once the queue is written
+            self.members.remove(&peer_id);
+            self.deliver(
+                peer_id,
+                None,
+                &relay_line(&RelayMessage::PeerLeft { peer_id }),
+            );
+        }
+    }
+}
+
+// rooms are created on first join and dropped when the last member leaves
+type Rooms = Arc<Mutex<HashMap<String, Room>>>;
+
+// how long a stopping relay waits for its clients to disconnect
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how many messages may wait for a client before it counts as fallen behind
+const PEER_QUEUE_LENGTH: usize = 256;
+
+// TCP keepalive probes on idle client connections, so a client that vanished without closing its
+// connection (power loss, a dropped network) is noticed and announced as left within about a minute
+const KEEPALIVE_IDLE: Duration = Duration::from_secs(20);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// how long a new connection has to start talking before it is taken for a TCP client that waits
+// for its Welcome. WebSocket clients always open with their handshake
+const TRANSPORT_SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+// how long a connection has to finish its TLS handshake, one that never sends a ClientHello
+// would otherwise hold its task forever
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how the relay treats the connections it accepts
+#[derive(Clone, Default)]
+pub struct RelayOptions {
+    // TLS handshake on every connection before either transport, None serves plain TCP
+    pub tls: Option<TlsAcceptor>,
+    // clients must join with a token signed with this, None lets anyone join any room
+    pub auth_secret: Option<Arc<[u8]>>,
+}
+
+// accepts signaling clients on the listener until accepting fails.
+// the relay binary runs this on a fixed address, tests on an ephemeral port
+pub async fn run_relay(tcp_listener: TcpListener) -> std::io::Result<()> {
+    run_relay_until(
+        tcp_listener,
+        RelayOptions::default(),
+        std::future::pending(),
+    )
+    .await
+}
+
+// run_relay until shutdown resolves. it then stops accepting, tells every client the relay is
+// going away and relays on for up to DRAIN_TIMEOUT while they disconnect
+pub async fn run_relay_until(
+    tcp_listener: TcpListener,
+    options: RelayOptions,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let rooms: Rooms = Arc::new(Mutex::new(HashMap::new()));
+    let mut next_peer_id: PeerId = 1;
+    let (stopping, _) = watch::channel(());
+    let mut clients = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        // accept incoming socket connections
+        let (tcp_stream, socket_addr) = tokio::select! {
+            accepted = tcp_listener.accept() => accepted?,
+            // reap the tasks of clients that have left
+            Some(_) = clients.join_next() => continue,
+            () = &mut shutdown => break,
+        };
+        println!(
+            "TcpListener accepted a connection. Client address: {}",
+            socket_addr
+        );
+
+        if let Err(err) = set_keepalive(&tcp_stream) {
+            eprintln!("Could not enable keepalive for {}: {}", socket_addr, err);
+        }
+
+        let peer_id = next_peer_id;
+        next_peer_id += 1;
+
+        let rooms = rooms.clone();
+        let stopping = stopping.subscribe();
+        let options = options.clone();
+        clients.spawn(async move {
+            let stream: Box<dyn RelayStream> = match &options.tls {
+                Some(tls) => {
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(tcp_stream)).await
+                    {
+                        Ok(Ok(tls_stream)) => Box::new(tls_stream),
+                        Ok(Err(err)) => {
+                            eprintln!("TLS handshake with {} failed: {}", socket_addr, err);
+                            return;
+                        }
+                        Err(_) => {
+                            eprintln!(
+                                "TLS handshake with {} timed out after {:?}",
+                                socket_addr, TLS_HANDSHAKE_TIMEOUT
+                            );
+                            return;
+                        }
+                    }
+                }
+                None => Box::new(tcp_stream),
+            };
+            let auth_secret = options.auth_secret.as_deref();
+            let result =
+                handle_client(stream, rooms, socket_addr, peer_id, auth_secret, stopping).await;
+            match result {
+                Ok(_) => println!("handle_client terminated gracefully"),
+                Err(error) => eprintln!("handle_client returned an error: {:?}", error),
+            }
+        });
+    }
+
+    drop(tcp_listener);
+    println!(
+        "Relay shutting down, notifying {} client(s).",
+        clients.len()
+    );
+    stopping.send_replace(());
+
+    let drain = async { while clients.join_next().await.is_some() {} };
+    if tokio::time::timeout(DRAIN_TIMEOUT, drain).await.is_err() {
+        // dropping the set aborts their tasks
+        eprintln!(
+            "{} client(s) still connected after {:?}, dropping them.",
+            clients.len(),
+            DRAIN_TIMEOUT
+        );
+    }
+    Ok(())
+}
+
+fn set_keepalive(tcp_stream: &TcpStream) -> std::io::Result<()> {
+    let keepalive = TcpKeepalive::new()
+        .with_time(KEEPALIVE_IDLE)
+        .with_interval(KEEPALIVE_INTERVAL);
+    SockRef::from(tcp_stream).set_tcp_keepalive(&keepalive)
+}
+
+// the room a client currently belongs to, along with the queue of messages for it
+struct Membership {
+    room: String,
+    receiver: Receiver<String>,
+}
+
+impl Membership {
+    // joins the room, announcing the new peer to the other members.
+    // returns the peers that were already there so they can be announced to the new one
+    fn join(rooms: &Rooms, room: &str, peer_id: PeerId) -> (Self, Vec<PeerId>) {
+        let mut rooms = rooms.lock().unwrap();
+        let entry = rooms.entry(room.to_string()).or_insert_with(|| {
+            println!("Room {} created.", room);
+            Room {
+                members: HashMap::new(),
+            }
+        });
+
+        let existing = entry.members.keys().copied().collect();
+        entry.deliver(
+            peer_id,
+            None,
+            &relay_line(&RelayMessage::PeerJoined { peer_id }),
+        );
+
+        let (sender, receiver) = mpsc::channel(PEER_QUEUE_LENGTH);
+        entry.members.insert(peer_id, sender);
+
+        let membership = Membership {
+            room: room.to_string(),
+            receiver,
+        };
+        (membership, existing)
+    }
+
+    fn leave(self, rooms: &Rooms, peer_id: PeerId) {
+        let mut rooms = rooms.lock().unwrap();
+        drop(self.receiver);
+
+        let Some(entry) = rooms.get_mut(&self.room) else {
+            return;
+        };
+        // a peer dropped for falling behind has been announced already
+        let was_member = entry.members.remove(&peer_id).is_some();
+
+        if entry.members.is_empty() {
+            rooms.remove(&self.room);
+            println!("Room {} closed.", self.room);
+        } else if was_member {
+            entry.deliver(
+                peer_id,
+                None,
+                &relay_line(&RelayMessage::PeerLeft { peer_id }),
+            );
+        }
+    }
+
+    // queues msg from peer_id for to, or for the rest of the room when to is None
+    fn send(&self, rooms: &Rooms, from: PeerId, to: Option<PeerId>, msg: &RelayMessage) {
+        let mut rooms = rooms.lock().unwrap();
+        if let Some(entry) = rooms.get_mut(&self.room) {
+            entry.deliver(from, to, &relay_line(msg));
+        }
+    }
+}
+
+fn relay_line(msg: &RelayMessage) -> String {
+    codec::encode(msg).unwrap()
+}
+
+async fn handle_client(
+    stream: Box<dyn RelayStream>,
+    rooms: Rooms,
+    socket_addr: SocketAddr,
+    peer_id: PeerId,
+    auth_secret: Option<&[u8]>,
+    stopping: watch::Receiver<()>,
+) -> Result<(), CodecError> {
+    println!("Client {} assigned peer id {}.", socket_addr, peer_id);
+    let mut membership: Option<Membership> = None;
+
+    // both transports share the port. a WebSocket handshake is an HTTP GET, a line of the TCP
+    // transport is a JSON object
+    let mut stream = BufReader::new(stream);
+    let websocket = match tokio::time::timeout(TRANSPORT_SNIFF_TIMEOUT, stream.fill_buf()).await {
+        Ok(read) => match read?.first() {
+            Some(byte) => *byte == b'G',
+            // closed without a word
+            None => return Ok(()),
+        },
+        Err(_) => false,
+    };
+    let result = if websocket {
+        let ws_stream = tokio_tungstenite::accept_async(stream).await?;
+        println!("Client {} speaks WebSocket.", socket_addr);
+        let (sink, ws_stream) = ws_stream.split();
+        let reader = WsFrameReader::new(ws_stream, DEFAULT_MAX_LINE_LENGTH);
+        let writer = WsFrameWriter::new(sink);
+        relay_messages(
+            reader,
+            writer,
+            &rooms,
+            &mut membership,
+            peer_id,
+            auth_secret,
+            stopping,
+        )
+        .await
+    } else {
+        let (reader, writer) = tokio::io::split(stream);
+        let reader = FrameReader::new(reader, DEFAULT_MAX_LINE_LENGTH);
+        let writer = FrameWriter::new(writer);
+        relay_messages(
+            reader,
+            writer,
+            &rooms,
+            &mut membership,
+            peer_id,
+            auth_secret,
+            stopping,
+        )
+        .await
+    };
+
+    if let Some(membership) = membership {
+        membership.leave(&rooms, peer_id);
+    }
+
+    result
+}
+
+// waits for the next message queued for the client, or forever while it has not joined a room
+// yet. None once the client has been dropped from its room
+async fn next_room_message(membership: &mut Option<Membership>) -> Option<String> {
+    match membership {
+        Some(membership) => membership.receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn relay_messages<R: ReadFrames, W: WriteFrames>(
+    mut reader: R,
+    mut writer: W,
+    rooms: &Rooms,
+    membership: &mut Option<Membership>,
+    peer_id: PeerId,
+    auth_secret: Option<&[u8]>,
+    mut stopping: watch::Receiver<()>,
+) -> Result<(), CodecError> {
+    // announce the peer id so the client knows how others will address it
+    writer
+        .write_frame(&RelayMessage::Welcome { peer_id })
+        .await?;
+
+    // what the client's token lets it do, None while it has not joined or the relay has no secret
+    let mut role: Option<Role> = None;
+    let mut notified = false;
+    loop {
+        tokio::select! {
+            // the relay is stopping. tell the client once, then keep relaying until it disconnects
+            _ = stopping.changed(), if !notified => {
+                notified = true;
+                writer.write_frame(&RelayMessage::ShuttingDown).await?;
+            }
+
+            // write what the room queued for this client
+            queued = next_room_message(membership) => {
+                match queued {
+                    Some(payload) => writer.write_encoded(&payload).await?,
+                    // it fell behind, reconnecting gets it the room's current state
+                    None => {
+                        println!("Peer {} was dropped from its room.", peer_id);
+                        break;
+                    }
+                }
+            }
+
+            // read a frame from socket
+            socket_read_result = reader.read_frame::<ClientMessage>() => {
+                println!("Message received from this client's socket.");
+                let msg = match socket_read_result {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => {
+                        println!("Socket closed. Client disconnected.");
+                        break;
+                    }
+                    Err(error) if error.is_fatal() => return Err(error),
+                    Err(error) => {
+                        eprintln!("Bad message from peer {}: {}", peer_id, error);
+                        continue;
+                    }
+                };
+                println!("{:?}", msg);
+
+                match msg {
+                    ClientMessage::Join { room, token } => {
+                        if let Some(secret) = auth_secret {
+                            match auth::authorize(secret, &room, token.as_deref()) {
+                                Ok(granted) => role = Some(granted),
+                                Err(err) => {
+                                    reject(&mut writer, peer_id, err.to_string()).await?;
+                                    break;
+                                }
+                            }
+                        }
+
+                        println!("Peer {} joining room {} as {:?}.", peer_id, room, role);
+                        if let Some(previous) = membership.take() {
+                            previous.leave(rooms, peer_id);
+                        }
+
+                        let (joined, existing) = Membership::join(rooms, &room, peer_id);
+                        *membership = Some(joined);
+
+                        for existing_peer in existing {
+                            let msg = RelayMessage::PeerJoined { peer_id: existing_peer };
+                            writer.write_frame(&msg).await?;
+                        }
+                    }
+                    ClientMessage::Send { to, signal } if !may_send(role, to, &signal) => {
+                        eprintln!(
+                            "Dropping {:?} to {:?} from peer {} as {:?}.",
+                            signal, to, peer_id, role
+                        );
+                    }
+                    ClientMessage::Send { to, signal } => match membership {
+                        Some(membership) => {
+                            // stamp the sender so the receiving peer knows who to reply to
+                            let msg = RelayMessage::Deliver { from: peer_id, signal };
+                            membership.send(rooms, peer_id, to, &msg);
+                        }
+                        None if auth_secret.is_some() => {
+                            reject(&mut writer, peer_id, "join a room with a token first".to_string()).await?;
+                            break;
+                        }
+                        None => eprintln!("Peer {} sent a signal before joining a room.", peer_id),
+                    },
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// whether a client with role may have signal relayed to to. only publishers start sessions, a
+// viewer answers and says bye to one producer rather than the room, and the signals a pipeline
+// generates locally never come from a peer. without auth there are no roles
+fn may_send(role: Option<Role>, to: Option<PeerId>, signal: &Signal) -> bool {
+    match signal {
+        Signal::IceCandidate { .. } | Signal::Capabilities { .. } | Signal::Leave => true,
+        Signal::Answer(_) | Signal::Bye => role != Some(Role::Viewer) || to.is_some(),
+        Signal::Offer(_) => role != Some(Role::Viewer),
+        Signal::PeerJoined
+        | Signal::PeerLeft
+        | Signal::Reconnected
+        | Signal::StartRecording
+        | Signal::StopRecording
+        | Signal::Shutdown => false,
+    }
+}
+
+// tells the client why before closing the connection
+async fn reject<W: WriteFrames>(
+    writer: &mut W,
+    peer_id: PeerId,
+    reason: String,
+) -> Result<(), CodecError> {
+    eprintln!("Rejecting peer {}: {}", peer_id, reason);
+    writer
+        .write_frame(&RelayMessage::Rejected { reason })
+        .await?;
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use tokio::{
+        io::{ReadHalf, WriteHalf},
+        time::timeout,
+    };
+
+    use super::*;
+    use crate::auth::Claims;
+
+    const SECRET: &[u8] = b"relay secret";
+    const ROOM: &str = "studio";
+    // long enough for the relay to pass a message on, short enough to wait out one it dropped
+    const WAIT: Duration = Duration::from_millis(500);
+
+    // a client speaking the TCP transport, as peercomms does
+    struct Client {
+        reader: FrameReader<ReadHalf<TcpStream>>,
+        writer: FrameWriter<WriteHalf<TcpStream>>,
+    }
+
+    impl Client {
+        // connects and joins the room, leaving the Welcome unread
+        async fn join(address: SocketAddr, token: Option<String>) -> Client {
+            let (reader, writer) = tokio::io::split(TcpStream::connect(address).await.unwrap());
+            let mut client = Client {
+                reader: FrameReader::new(reader, DEFAULT_MAX_LINE_LENGTH),
+                writer: FrameWriter::new(writer),
+            };
+            let join = ClientMessage::Join {
+                room: ROOM.to_string(),
+                token,
+            };
+            client.writer.write_frame(&join).await.unwrap();
+            client
+        }
+
+        async fn send(&mut self, signal: Signal) {
+            self.send_to(None, signal).await;
+        }
+
+        async fn send_to(&mut self, to: Option<PeerId>, signal: Signal) {
+            let msg = ClientMessage::Send { to, signal };
+            self.writer.write_frame(&msg).await.unwrap();
+        }
+
+        // the next message, None once the relay has closed the connection
+        async fn recv(&mut self) -> Option<RelayMessage> {
+            timeout(WAIT, self.reader.read_frame::<RelayMessage>())
+                .await
+                .expect("relay sent nothing")
+                .unwrap()
+        }
+
+        async fn welcome(&mut self) -> PeerId {
+            match self.recv().await {
+                Some(RelayMessage::Welcome { peer_id }) => peer_id,
+                other => panic!("expected a welcome, got {:?}", other),
+            }
+        }
+
+        // the signal the relay delivers next
+        async fn delivered(&mut self) -> Signal {
+            match self.recv().await {
+                Some(RelayMessage::Deliver { signal, .. }) => signal,
+                other => panic!("expected a signal, got {:?}", other),
+            }
+        }
+    }
+
+    async fn start_relay() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let options = RelayOptions {
+            auth_secret: Some(SECRET.into()),
+            ..RelayOptions::default()
+        };
+        tokio::spawn(run_relay_until(listener, options, std::future::pending()));
+        address
+    }
+
+    fn token(room: &str, role: Role) -> Option<String> {
+        Some(auth::mint(SECRET, &Claims::new(room, role, 60)))
+    }
+
+    #[tokio::test]
+    async fn clients_without_a_valid_token_are_rejected() {
+        let address = start_relay().await;
+        let expired = Claims {
+            room: ROOM.to_string(),
+            role: Role::Publisher,
+            expires_at: UNIX_EPOCH.elapsed().unwrap().as_secs() - 1,
+        };
+        let forged = auth::mint(b"another secret", &Claims::new(ROOM, Role::Publisher, 60));
+
+        for token in [
+            None,
+            Some(auth::mint(SECRET, &expired)),
+            token("lobby", Role::Publisher),
+            Some(forged),
+        ] {
+            let mut client = Client::join(address, token).await;
+            client.welcome().await;
+            assert!(matches!(
+                client.recv().await,
+                Some(RelayMessage::Rejected { .. })
+            ));
+            assert!(client.recv().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn viewers_cannot_start_or_end_sessions_for_others() {
+        let address = start_relay().await;
+        let mut publisher = Client::join(address, token(ROOM, Role::Publisher)).await;
+        let publisher_id = publisher.welcome().await;
+        let mut viewer = Client::join(address, token(ROOM, Role::Viewer)).await;
+        let viewer_id = viewer.welcome().await;
+        assert!(matches!(
+            viewer.recv().await,
+            Some(RelayMessage::PeerJoined { .. })
+        ));
+        assert!(matches!(
+            publisher.recv().await,
+            Some(RelayMessage::PeerJoined { peer_id }) if peer_id == viewer_id
+        ));
+
+        viewer.send(Signal::Offer("v=0".to_string())).await;
+        // local signals don't even encode, so write one out by hand
+        viewer
+            .writer
+            .write_encoded("{\"Send\":{\"to\":null,\"signal\":\"Reconnected\"}}\n")
+            .await
+            .unwrap();
+        // answers and byes go to one producer, not the whole room
+        viewer.send(Signal::Bye).await;
+        viewer.send(Signal::Answer("v=0".to_string())).await;
+        viewer
+            .send_to(Some(publisher_id), Signal::Answer("v=0".to_string()))
+            .await;
+        assert!(matches!(publisher.delivered().await, Signal::Answer(_)));
+        viewer.send_to(Some(publisher_id), Signal::Bye).await;
+        assert!(matches!(publisher.delivered().await, Signal::Bye));
+
+        publisher.send(Signal::Offer("v=0".to_string())).await;
+        assert!(matches!(viewer.delivered().await, Signal::Offer(_)));
+    }
+
+    #[tokio::test]
+    async fn signals_before_joining_are_rejected() {
+        let address = start_relay().await;
+        let (reader, writer) = tokio::io::split(TcpStream::connect(address).await.unwrap());
+        let mut client = Client {
+            reader: FrameReader::new(reader, DEFAULT_MAX_LINE_LENGTH),
+            writer: FrameWriter::new(writer),
+        };
+
+        client.send(Signal::Bye).await;
+        client.welcome().await;
+        assert!(matches!(
+            client.recv().await,
+            Some(RelayMessage::Rejected { .. })
+        ));
+    }
+}
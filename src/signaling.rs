@@ -0,0 +1,235 @@
+use std::{future::Future, io};
+
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+use crate::{
+    Envelope, PeerId, Signal,
+    config::{Config, Transport},
+    peercomms::{ReconnectPolicy, run_peer_socket},
+};
+
+// what carries a pipeline's signals to its remote peers and theirs back.
+// the pipelines only see the two channels, so apps can plug in their own signaling
+pub trait SignalingTransport {
+    // runs until the pipeline stops, which it signals by closing from_pipeline.
+    // incoming signals name the peer they came from, and PeerJoined/PeerLeft announce peers
+    // the way the relay does
+    fn run(
+        self,
+        to_pipeline: Sender<Envelope>,
+        from_pipeline: Receiver<Envelope>,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+// signaling through the relay, reconnecting as run_peer_socket does
+pub struct RelaySignaling {
+    config: Config,
+    policy: ReconnectPolicy,
+}
+
+impl RelaySignaling {
+    // uses the transport and reconnect settings in the config
+    pub fn new(config: Config) -> Self {
+        RelaySignaling {
+            policy: ReconnectPolicy::from(&config.reconnect),
+            config,
+        }
+    }
+
+    pub fn tcp(mut config: Config) -> Self {
+        config.transport = Transport::Tcp;
+        RelaySignaling::new(config)
+    }
+
+    pub fn websocket(mut config: Config) -> Self {
+        config.transport = Transport::WebSocket;
+        RelaySignaling::new(config)
+    }
+
+    pub fn with_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl SignalingTransport for RelaySignaling {
+    async fn run(
+        self,
+        to_pipeline: Sender<Envelope>,
+        from_pipeline: Receiver<Envelope>,
+    ) -> io::Result<()> {
+        run_peer_socket(&self.config, &self.policy, to_pipeline, from_pipeline).await
+    }
+}
+
+// ids the two ends of a loopback go by
+const LOOPBACK_FIRST: PeerId = 1;
+const LOOPBACK_SECOND: PeerId = 2;
+
+// one end of an in-memory link between two pipelines in the same process, see loopback
+pub struct LoopbackSignaling {
+    peer_id: PeerId,
+    remote_id: PeerId,
+    to_remote: UnboundedSender<Envelope>,
+    from_remote: UnboundedReceiver<Envelope>,
+}
+
+// two ends that behave like a relay room with just the two of them in it. each end announces the
+// other as joined when it starts and as left when the other's pipeline stops. signals sent before
+// the other end runs wait for it, so nothing depends on which side starts first
+pub fn loopback() -> (LoopbackSignaling, LoopbackSignaling) {
+    let (to_second, from_first) = unbounded_channel();
+    let (to_first, from_second) = unbounded_channel();
+
+    let first = LoopbackSignaling {
+        peer_id: LOOPBACK_FIRST,
+        remote_id: LOOPBACK_SECOND,
+        to_remote: to_second,
+        from_remote: from_second,
+    };
+    let second = LoopbackSignaling {
+        peer_id: LOOPBACK_SECOND,
+        remote_id: LOOPBACK_FIRST,
+        to_remote: to_first,
+        from_remote: from_first,
+    };
+    (first, second)
+}
+
+impl SignalingTransport for LoopbackSignaling {
+    async fn run(
+        mut self,
+        to_pipeline: Sender<Envelope>,
+        mut from_pipeline: Receiver<Envelope>,
+    ) -> io::Result<()> {
+        let joined = Envelope {
+            peer: Some(self.remote_id),
+            signal: Signal::PeerJoined,
+        };
+        if to_pipeline.send(joined).await.is_ok() {
+            loop {
+                tokio::select! {
+                    msg_result = from_pipeline.recv() => match msg_result {
+                        // addressed to the room or the remote end, nobody else is there
+                        Some(envelope) if envelope.peer.is_none_or(|to| to == self.remote_id) => {
+                            // the remote end may have stopped already
+                            let _ = self.to_remote.send(Envelope {
+                                peer: Some(self.peer_id),
+                                signal: envelope.signal,
+                            });
+                        }
+                        Some(envelope) => {
+                            eprintln!("Dropping signal for unknown peer {:?}.", envelope.peer);
+                        }
+                        None => break,
+                    },
+
+                    // disabled once the remote end has stopped and everything it sent is delivered
+                    Some(envelope) = self.from_remote.recv() => {
+                        if to_pipeline.send(envelope).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        // as the relay would once our connection closed
+        let _ = self.to_remote.send(Envelope {
+            peer: Some(self.peer_id),
+            signal: Signal::PeerLeft,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+
+    // runs an end of a loopback, returning what a pipeline would hold: the sender of its
+    // outgoing signals and the receiver of its incoming ones
+    fn run_end(end: LoopbackSignaling) -> (Sender<Envelope>, Receiver<Envelope>) {
+        let (to_pipeline, pipeline_in) = channel(10);
+        let (pipeline_out, from_pipeline) = channel(10);
+        tokio::spawn(end.run(to_pipeline, from_pipeline));
+        (pipeline_out, pipeline_in)
+    }
+
+    fn offer() -> Envelope {
+        Envelope {
+            peer: None,
+            signal: Signal::Offer("v=0".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn ends_announce_each_other_and_deliver_with_the_sender() {
+        let (first, second) = loopback();
+        let (first_out, mut first_in) = run_end(first);
+
+        // sent before the other end runs, it waits for it
+        first_out.send(offer()).await.unwrap();
+        let (second_out, mut second_in) = run_end(second);
+
+        let joined = first_in.recv().await.unwrap();
+        assert!(matches!(
+            joined,
+            Envelope {
+                peer: Some(2),
+                signal: Signal::PeerJoined
+            }
+        ));
+        let joined = second_in.recv().await.unwrap();
+        assert!(matches!(
+            joined,
+            Envelope {
+                peer: Some(1),
+                signal: Signal::PeerJoined
+            }
+        ));
+        let received = second_in.recv().await.unwrap();
+        assert!(matches!(
+            received,
+            Envelope {
+                peer: Some(1),
+                signal: Signal::Offer(_)
+            }
+        ));
+
+        let answer = Envelope {
+            peer: Some(1),
+            signal: Signal::Answer("v=0".to_string()),
+        };
+        second_out.send(answer).await.unwrap();
+        let received = first_in.recv().await.unwrap();
+        assert!(matches!(
+            received,
+            Envelope {
+                peer: Some(2),
+                signal: Signal::Answer(_)
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn stopped_pipeline_is_announced_as_left() {
+        let (first, second) = loopback();
+        let (first_out, _first_in) = run_end(first);
+        let (_second_out, mut second_in) = run_end(second);
+
+        drop(first_out);
+        let joined = second_in.recv().await.unwrap();
+        assert!(matches!(joined.signal, Signal::PeerJoined));
+        let left = second_in.recv().await.unwrap();
+        assert!(matches!(
+            left,
+            Envelope {
+                peer: Some(1),
+                signal: Signal::PeerLeft
+            }
+        ));
+    }
+}
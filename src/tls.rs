@@ -0,0 +1,143 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    TlsAcceptor, TlsConnector, client,
+    rustls::{
+        self, ClientConfig, RootCertStore, ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+    },
+};
+
+// TLS under the signaling transports. the relay and its clients wrap the TCP connection before
+// either framing runs on top, so TCP and WebSocket clients are covered alike
+
+// a connection to or from the relay, with or without TLS
+pub(crate) trait RelayStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> RelayStream for S {}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Pem {
+        path: PathBuf,
+        err: rustls::pki_types::pem::Error,
+    },
+    // the certificate file has no certificates in it
+    NoCertificates {
+        path: PathBuf,
+    },
+    Rustls(rustls::Error),
+    // the relay address has no host a certificate could be issued for
+    InvalidServerName(String),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Pem { path, err } => {
+                write!(f, "could not read PEM file {}: {}", path.display(), err)
+            }
+            TlsError::NoCertificates { path } => {
+                write!(f, "no certificates in {}", path.display())
+            }
+            TlsError::Rustls(err) => write!(f, "TLS error: {}", err),
+            TlsError::InvalidServerName(host) => write!(f, "invalid TLS server name {}", host),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<rustls::Error> for TlsError {
+    fn from(err: rustls::Error) -> Self {
+        TlsError::Rustls(err)
+    }
+}
+
+// the relay's side, serving the certificate chain in cert_path with the key in key_path
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, TlsError> {
+    let certs = read_certificates(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|err| TlsError::Pem {
+        path: key_path.to_path_buf(),
+        err,
+    })?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// a client's side, checking the relay's certificate against the public web roots, or only the
+// CAs in ca_file when given
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl TlsClient {
+    pub fn new(relay_address: &str, ca_file: Option<&Path>) -> Result<Self, TlsError> {
+        let mut roots = RootCertStore::empty();
+        match ca_file {
+            Some(path) => {
+                for cert in read_certificates(path)? {
+                    roots.add(cert)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let host = relay_host(relay_address);
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|_| TlsError::InvalidServerName(host.to_string()))?;
+
+        Ok(TlsClient {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    pub async fn connect(&self, tcp_stream: TcpStream) -> io::Result<client::TlsStream<TcpStream>> {
+        self.connector
+            .connect(self.server_name.clone(), tcp_stream)
+            .await
+    }
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let pem_error = |err| TlsError::Pem {
+        path: path.to_path_buf(),
+        err,
+    };
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(pem_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pem_error)?;
+
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(certs)
+}
+
+// the host part of host:port, without the brackets around an IPv6 address
+fn relay_host(relay_address: &str) -> &str {
+    let host = relay_address
+        .rsplit_once(':')
+        .map_or(relay_address, |(host, _port)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
@@ -0,0 +1,60 @@
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc::Sender;
+
+use crate::{Envelope, Signal};
+
+// the process signals the binaries react to, turned into local Signals for the pipeline
+
+// resolves on the first Ctrl-C or SIGTERM
+pub async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+// Ctrl-C or SIGTERM shuts the pipeline down gracefully, a second one exits straight away
+pub async fn forward_shutdown_signals(send_to_gst: Sender<Envelope>) {
+    if let Err(err) = shutdown_signal().await {
+        eprintln!("could not listen for Ctrl-C/SIGTERM: {}", err);
+        return;
+    }
+
+    println!("shutting down, press Ctrl-C again to exit immediately.");
+    let envelope = Envelope {
+        peer: None,
+        signal: Signal::Shutdown,
+    };
+    if send_to_gst.send(envelope).await.is_err() {
+        return;
+    }
+
+    if shutdown_signal().await.is_ok() {
+        eprintln!("exiting without finishing shutdown.");
+        std::process::exit(1);
+    }
+}
+
+// SIGUSR1 starts recording and SIGUSR2 stops it, without interrupting the stream
+pub async fn forward_recording_signals(send_to_gst: Sender<Envelope>) {
+    let (Ok(mut start), Ok(mut stop)) = (
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+    ) else {
+        eprintln!("could not listen for SIGUSR1/SIGUSR2, recording can't be toggled.");
+        return;
+    };
+
+    loop {
+        let signal = tokio::select! {
+            Some(()) = start.recv() => Signal::StartRecording,
+            Some(()) = stop.recv() => Signal::StopRecording,
+            else => return,
+        };
+        let envelope = Envelope { peer: None, signal };
+        if send_to_gst.send(envelope).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use gst::Element;
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_webrtc::WebRTCICETransportPolicy;
+
+use crate::config::{IceConfig, IceTransportPolicy};
+
+// points a webrtcbin at the configured STUN/TURN servers
+pub fn configure_ice(webrtc_bin: &Element, ice: &IceConfig) {
+    // webrtcbin only takes a single STUN server
+    if let Some(stun_server) = ice.stun_servers.first() {
+        webrtc_bin.set_property("stun-server", stun_server.as_str());
+    }
+    if ice.stun_servers.len() > 1 {
+        eprintln!(
+            "webrtcbin supports one STUN server, ignoring all but {}",
+            ice.stun_servers[0]
+        );
+    }
+
+    for turn_server in &ice.turn_servers {
+        let added = webrtc_bin.emit_by_name::<bool>("add-turn-server", &[&turn_server.uri()]);
+        if !added {
+            eprintln!("webrtcbin rejected TURN server {}", turn_server.url);
+        }
+    }
+
+    let policy = match ice.transport_policy {
+        IceTransportPolicy::All => WebRTCICETransportPolicy::All,
+        IceTransportPolicy::Relay => WebRTCICETransportPolicy::Relay,
+    };
+    webrtc_bin.set_property("ice-transport-policy", policy);
+}
@@ -0,0 +1,80 @@
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error, Message};
+
+use crate::codec::{CodecError, ReadFrames, WriteFrames};
+
+// the WebSocket framing of the signaling protocol: the same JSON messages as the TCP transport,
+// one per text message instead of one per line, so browsers can take part
+
+pub struct WsFrameReader<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    max_message_length: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsFrameReader<S> {
+    pub fn new(stream: SplitStream<WebSocketStream<S>>, max_message_length: usize) -> Self {
+        WsFrameReader {
+            stream,
+            max_message_length,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ReadFrames for WsFrameReader<S> {
+    async fn read_frame<T: DeserializeOwned>(&mut self) -> Result<Option<T>, CodecError> {
+        loop {
+            let msg = match self.stream.next().await {
+                Some(Ok(msg)) => msg,
+                // a client that drops the socket without a close message has still left
+                Some(Err(Error::ConnectionClosed | Error::AlreadyClosed)) | None => {
+                    return Ok(None);
+                }
+                Some(Err(err)) => return Err(err.into()),
+            };
+
+            // browsers send text, binary messages get the benefit of the doubt
+            let payload = match &msg {
+                Message::Text(text) => text.as_bytes(),
+                Message::Binary(data) => data.as_ref(),
+                Message::Close(_) => return Ok(None),
+                // tungstenite answers pings itself
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            };
+
+            if payload.len() > self.max_message_length {
+                return Err(CodecError::LineTooLong {
+                    max_line_length: self.max_message_length,
+                });
+            }
+            return Ok(Some(serde_json::from_slice(payload)?));
+        }
+    }
+}
+
+pub struct WsFrameWriter<S> {
+    sink: SplitSink<WebSocketStream<S>, Message>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsFrameWriter<S> {
+    pub fn new(sink: SplitSink<WebSocketStream<S>, Message>) -> Self {
+        WsFrameWriter { sink }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WriteFrames for WsFrameWriter<S> {
+    async fn write_encoded(&mut self, line: &str) -> Result<(), CodecError> {
+        // the message marks the end of the frame, the line's newline isn't needed
+        self.sink.send(Message::text(line.trim_end())).await?;
+        Ok(())
+    }
+
+    // sends a close message, the other side answers with its own and closes the connection
+    async fn shutdown(&mut self) -> Result<(), CodecError> {
+        self.sink.close().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,563 @@
+use std::{io, path::Path, thread, time::Duration};
+
+use gstreamer::{self as gst, ElementFactory};
+use gstreamer_webrtc::WebRTCICEConnectionState;
+use livestream_build::{
+    Envelope, Signal,
+    auth::{self, Claims, Role},
+    config::{Config, IceConfig, SourceConfig, SourceKind, Transport},
+    error::Error,
+    mediaconsumer::{ConsumerOutput, FrameCallbacks, run_consumer_pipeline},
+    mediaproducer::run_producer_pipeline,
+    relay::{RelayOptions, run_relay, run_relay_until},
+    signaling::{self, RelaySignaling, SignalingTransport},
+    tls,
+};
+use tokio::{
+    net::TcpListener,
+    sync::mpsc::{
+        Receiver, Sender, UnboundedReceiver, UnboundedSender, channel, unbounded_channel,
+    },
+    time::timeout,
+};
+
+// relay, producer and headless consumer all running in this process, talking over loopback
+
+const DEADLINE: Duration = Duration::from_secs(30);
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+
+// enough frames to know media keeps flowing rather than a single buffer slipping through
+const MIN_FRAMES: usize = 5;
+
+// everything the two pipelines need, besides whatever decoder decodebin picks for H.264
+const REQUIRED_ELEMENTS: &[&str] = &[
+    "webrtcbin",
+    "nicesrc",
+    "dtlssrtpenc",
+    "videotestsrc",
+    "audiotestsrc",
+    "x264enc",
+    "opusenc",
+    "opusdec",
+    "rtph264pay",
+    "rtpopuspay",
+    "rtph264depay",
+];
+
+// what simulcast adds on top, scaling the layers and tagging them with their RID
+const SIMULCAST_ELEMENTS: &[&str] = &["videoscale", "rtphdrextstreamid"];
+
+#[derive(Debug)]
+enum Event {
+    Answered,
+    IceConnectionState(WebRTCICEConnectionState),
+    VideoFrame {
+        width: Option<i32>,
+        height: Option<i32>,
+    },
+    AudioSamples,
+}
+
+#[derive(Debug)]
+struct Progress {
+    // the frame size the consumer should decode
+    width: u32,
+    height: u32,
+    answered: bool,
+    ice_connected: bool,
+    video_frames: usize,
+    audio_buffers: usize,
+}
+
+impl Progress {
+    fn new(width: u32, height: u32) -> Self {
+        Progress {
+            width,
+            height,
+            answered: false,
+            ice_connected: false,
+            video_frames: 0,
+            audio_buffers: 0,
+        }
+    }
+
+    fn record(&mut self, event: Event) {
+        match event {
+            Event::Answered => self.answered = true,
+            Event::IceConnectionState(state) => {
+                if matches!(
+                    state,
+                    WebRTCICEConnectionState::Connected | WebRTCICEConnectionState::Completed
+                ) {
+                    self.ice_connected = true;
+                }
+            }
+            // frames still arriving from a session that is ending don't count
+            Event::VideoFrame { .. } | Event::AudioSamples if !self.answered => {}
+            Event::VideoFrame { width, height } => {
+                assert_eq!(width, Some(self.width as i32));
+                assert_eq!(height, Some(self.height as i32));
+                self.video_frames += 1;
+            }
+            Event::AudioSamples => self.audio_buffers += 1,
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.answered
+            && self.ice_connected
+            && self.video_frames >= MIN_FRAMES
+            && self.audio_buffers >= MIN_FRAMES
+    }
+}
+
+// set to skip the tests on machines without the GStreamer elements, which otherwise fail them
+const SKIP_ENV: &str = "LIVESTREAM_SKIP_MEDIA_TESTS";
+
+// whether the test can run, panicking when elements are missing unless skipping was asked for
+fn require_elements(elements: &[&'static str]) -> bool {
+    gst::init().unwrap();
+    let missing: Vec<_> = elements
+        .iter()
+        .copied()
+        .filter(|name| ElementFactory::find(name).is_none())
+        .collect();
+    if missing.is_empty() {
+        return true;
+    }
+
+    assert!(
+        std::env::var_os(SKIP_ENV).is_some(),
+        "missing GStreamer elements {:?}, install them or set {} to skip",
+        missing,
+        SKIP_ENV
+    );
+    eprintln!("skipping, missing GStreamer elements: {:?}", missing);
+    false
+}
+
+fn test_config(relay_address: String) -> Config {
+    Config {
+        relay_address,
+        room: "end-to-end".to_string(),
+        // host candidates are all loopback needs
+        ice: IceConfig {
+            stun_servers: Vec::new(),
+            turn_servers: Vec::new(),
+            ..IceConfig::default()
+        },
+        source: SourceConfig {
+            kind: SourceKind::Test,
+            width: WIDTH,
+            height: HEIGHT,
+            ..SourceConfig::default()
+        },
+        ..Config::default()
+    }
+}
+
+fn headless_callbacks(events: &UnboundedSender<Event>) -> FrameCallbacks {
+    let ice_events = events.clone();
+    let video_events = events.clone();
+    let audio_events = events.clone();
+
+    FrameCallbacks {
+        on_ice_connection_state: Box::new(move |state| {
+            let _ = ice_events.send(Event::IceConnectionState(state));
+        }),
+        on_video_frame: Box::new(move |frame| {
+            let _ = video_events.send(Event::VideoFrame {
+                width: frame.width(),
+                height: frame.height(),
+            });
+        }),
+        on_audio_samples: Box::new(move |_| {
+            let _ = audio_events.send(Event::AudioSamples);
+        }),
+    }
+}
+
+// a pipeline thread and its signaling, started the way the binaries start them
+struct Peer {
+    // local signals, as the binaries' signal handlers send them
+    signals: Sender<Envelope>,
+    pipeline: thread::JoinHandle<()>,
+    // ends once every sender of the pipeline's outgoing signals is gone
+    outgoing: tokio::task::JoinHandle<()>,
+    signaling: tokio::task::JoinHandle<io::Result<()>>,
+}
+
+// starts a peer, with the pipeline's outgoing signals passing through a tap that reports answers
+fn spawn_peer<F, S>(
+    config: &Config,
+    signaling: S,
+    events: UnboundedSender<Event>,
+    run_pipeline: F,
+) -> Peer
+where
+    F: FnOnce(&Config, Sender<Envelope>, Receiver<Envelope>) -> Result<(), Error> + Send + 'static,
+    S: SignalingTransport + Send + 'static,
+{
+    let (send_to_tokio, mut pipeline_out) = channel::<Envelope>(10);
+    let (send_to_socket, tokio_recv) = channel::<Envelope>(10);
+    let (send_to_gst, gst_recv) = channel::<Envelope>(10);
+
+    let pipeline_config = config.clone();
+    let pipeline = thread::spawn(move || {
+        if let Err(err) = run_pipeline(&pipeline_config, send_to_tokio, gst_recv) {
+            eprintln!("pipeline failed: {}", err);
+        }
+    });
+
+    // keeps draining once signaling has stopped, so it only ends with the pipeline's senders
+    let outgoing = tokio::spawn(async move {
+        while let Some(envelope) = pipeline_out.recv().await {
+            if matches!(envelope.signal, Signal::Answer(_)) {
+                let _ = events.send(Event::Answered);
+            }
+            let _ = send_to_socket.send(envelope).await;
+        }
+    });
+
+    let signals = send_to_gst.clone();
+    let signaling = tokio::spawn(signaling.run(send_to_gst, tokio_recv));
+    Peer {
+        signals,
+        pipeline,
+        outgoing,
+        signaling,
+    }
+}
+
+async fn wait_until_decoding(event_recv: &mut UnboundedReceiver<Event>, mut progress: Progress) {
+    let result = timeout(DEADLINE, async {
+        while let Some(event) = event_recv.recv().await {
+            progress.record(event);
+            if progress.done() {
+                break;
+            }
+        }
+    })
+    .await;
+
+    assert!(
+        result.is_ok() && progress.done(),
+        "stream not up after {:?}: {:?}",
+        DEADLINE,
+        progress
+    );
+}
+
+// runs a producer and a headless consumer connected to the relay until the consumer has decoded
+// a few seconds of width x height video and audio
+async fn stream_until_decoding(
+    producer_config: Config,
+    consumer_config: Config,
+    width: u32,
+    height: u32,
+) {
+    let producer_signaling = RelaySignaling::new(producer_config.clone());
+    let consumer_signaling = RelaySignaling::new(consumer_config.clone());
+    stream_over(
+        (producer_config, producer_signaling),
+        (consumer_config, consumer_signaling),
+        width,
+        height,
+    )
+    .await;
+}
+
+// stream_until_decoding with each peer signaling over the given transport
+async fn stream_over(
+    (producer_config, producer_signaling): (Config, impl SignalingTransport + Send + 'static),
+    (consumer_config, consumer_signaling): (Config, impl SignalingTransport + Send + 'static),
+    width: u32,
+    height: u32,
+) {
+    let (events, mut event_recv) = unbounded_channel();
+
+    let callbacks = headless_callbacks(&events);
+    spawn_peer(
+        &consumer_config,
+        consumer_signaling,
+        events.clone(),
+        move |config, send, recv| {
+            run_consumer_pipeline(config, ConsumerOutput::Headless(callbacks), send, recv)
+        },
+    );
+    spawn_peer(
+        &producer_config,
+        producer_signaling,
+        events,
+        run_producer_pipeline,
+    );
+
+    wait_until_decoding(&mut event_recv, Progress::new(width, height)).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn producer_streams_to_headless_consumer() {
+    if !require_elements(REQUIRED_ELEMENTS) {
+        return;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = test_config(listener.local_addr().unwrap().to_string());
+    tokio::spawn(run_relay(listener));
+
+    stream_until_decoding(config.clone(), config, WIDTH, HEIGHT).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pipelines_negotiate_over_loopback_without_a_relay() {
+    if !require_elements(REQUIRED_ELEMENTS) {
+        return;
+    }
+
+    // nothing listens here, the pipelines only signal to each other
+    let config = test_config("127.0.0.1:9".to_string());
+    let (producer_end, consumer_end) = signaling::loopback();
+
+    stream_over(
+        (config.clone(), producer_end),
+        (config, consumer_end),
+        WIDTH,
+        HEIGHT,
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn simulcast_consumer_gets_requested_layer() {
+    if !require_elements(&[REQUIRED_ELEMENTS, SIMULCAST_ELEMENTS].concat()) {
+        return;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = test_config(listener.local_addr().unwrap().to_string());
+    tokio::spawn(run_relay(listener));
+
+    let mut producer_config = config.clone();
+    producer_config.video.simulcast = true;
+    // the half size layer
+    let mut consumer_config = config;
+    consumer_config.consumer.simulcast_layer = Some("m".to_string());
+
+    stream_until_decoding(producer_config, consumer_config, WIDTH / 2, HEIGHT / 2).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn websocket_consumer_streams_from_tcp_producer() {
+    if !require_elements(REQUIRED_ELEMENTS) {
+        return;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = test_config(listener.local_addr().unwrap().to_string());
+    tokio::spawn(run_relay(listener));
+
+    // the relay bridges the two transports within a room
+    let mut consumer_config = config.clone();
+    consumer_config.transport = Transport::WebSocket;
+
+    stream_until_decoding(config, consumer_config, WIDTH, HEIGHT).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_stream_through_a_tls_relay() {
+    if !require_elements(REQUIRED_ELEMENTS) {
+        return;
+    }
+
+    // a certificate for localhost and 127.0.0.1, issued by a test CA
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let acceptor = tls::acceptor(
+        &fixtures.join("relay-cert.pem"),
+        &fixtures.join("relay-key.pem"),
+    )
+    .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let options = RelayOptions {
+        tls: Some(acceptor),
+        ..RelayOptions::default()
+    };
+    tokio::spawn(run_relay_until(listener, options, std::future::pending()));
+
+    let mut config = test_config(format!("localhost:{}", port));
+    config.tls.ca_file = Some(fixtures.join("ca.pem"));
+    let mut consumer_config = config.clone();
+    consumer_config.transport = Transport::WebSocket;
+
+    stream_until_decoding(config, consumer_config, WIDTH, HEIGHT).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_with_tokens_stream_through_an_authenticating_relay() {
+    if !require_elements(REQUIRED_ELEMENTS) {
+        return;
+    }
+
+    const SECRET: &[u8] = b"end-to-end secret";
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = test_config(listener.local_addr().unwrap().to_string());
+    let options = RelayOptions {
+        auth_secret: Some(SECRET.into()),
+        ..RelayOptions::default()
+    };
+    tokio::spawn(run_relay_until(listener, options, std::future::pending()));
+
+    let token = |role| Some(auth::mint(SECRET, &Claims::new(&config.room, role, 60)));
+    let mut producer_config = config.clone();
+    producer_config.token = token(Role::Publisher);
+    let mut consumer_config = config.clone();
+    consumer_config.token = token(Role::Viewer);
+
+    stream_until_decoding(producer_config, consumer_config, WIDTH, HEIGHT).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn consumer_picks_up_a_replacement_producer() {
+    if !require_elements(REQUIRED_ELEMENTS) {
+        return;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = test_config(listener.local_addr().unwrap().to_string());
+    tokio::spawn(run_relay(listener));
+
+    let (events, mut event_recv) = unbounded_channel();
+    let callbacks = headless_callbacks(&events);
+    let signaling = || RelaySignaling::new(config.clone());
+    spawn_peer(
+        &config,
+        signaling(),
+        events.clone(),
+        move |config, send, recv| {
+            run_consumer_pipeline(config, ConsumerOutput::Headless(callbacks), send, recv)
+        },
+    );
+    let first_producer = spawn_peer(&config, signaling(), events.clone(), run_producer_pipeline);
+    wait_until_decoding(&mut event_recv, Progress::new(WIDTH, HEIGHT)).await;
+
+    // the first producer says bye on its way out, the consumer should then take the next offer
+    first_producer
+        .signals
+        .send(Envelope {
+            peer: None,
+            signal: Signal::Shutdown,
+        })
+        .await
+        .unwrap();
+    spawn_peer(&config, signaling(), events, run_producer_pipeline);
+    wait_until_decoding(&mut event_recv, Progress::new(WIDTH, HEIGHT)).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn recording_starts_a_new_file_at_the_duration_limit() {
+    let elements = [
+        REQUIRED_ELEMENTS,
+        &["splitmuxsink", "mp4mux", "h264parse", "opusparse"],
+    ];
+    if !require_elements(&elements.concat()) {
+        return;
+    }
+
+    let directory =
+        std::env::temp_dir().join(format!("livestream-recording-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    let mut config = test_config("127.0.0.1:9".to_string());
+    config.recording.enabled = true;
+    config.recording.directory = directory.clone();
+    config.recording.max_file_duration = Some(1);
+
+    // the producer records from startup whether or not anyone watches,
+    // the other end is only kept so the producer's signaling stays up
+    let (producer_end, _consumer_end) = signaling::loopback();
+    let (events, _event_recv) = unbounded_channel();
+    let producer = spawn_peer(&config, producer_end, events, run_producer_pipeline);
+
+    // a second of stream per file, the second file shows a split point was found
+    let files = || std::fs::read_dir(&directory).map_or(0, |entries| entries.count());
+    let result = timeout(DEADLINE, async {
+        while files() < 2 {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    })
+    .await;
+
+    let _ = producer
+        .signals
+        .send(Envelope {
+            peer: None,
+            signal: Signal::Shutdown,
+        })
+        .await;
+    assert!(
+        result.is_ok(),
+        "{} file(s) in {} after {:?}",
+        files(),
+        directory.display(),
+        DEADLINE
+    );
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_ends_the_pipeline_and_its_signaling() {
+    if !require_elements(REQUIRED_ELEMENTS) {
+        return;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = test_config(listener.local_addr().unwrap().to_string());
+    tokio::spawn(run_relay(listener));
+
+    let (events, mut event_recv) = unbounded_channel();
+    let callbacks = headless_callbacks(&events);
+    let consumer = spawn_peer(
+        &config,
+        RelaySignaling::new(config.clone()),
+        events.clone(),
+        move |config, send, recv| {
+            run_consumer_pipeline(config, ConsumerOutput::Headless(callbacks), send, recv)
+        },
+    );
+    let producer = spawn_peer(
+        &config,
+        RelaySignaling::new(config.clone()),
+        events,
+        run_producer_pipeline,
+    );
+    wait_until_decoding(&mut event_recv, Progress::new(WIDTH, HEIGHT)).await;
+
+    // the binaries exit once signaling returns, which waits on the pipeline dropping its senders
+    for (name, peer) in [("producer", producer), ("consumer", consumer)] {
+        let Peer {
+            signals,
+            pipeline,
+            outgoing,
+            signaling,
+        } = peer;
+        signals
+            .send(Envelope {
+                peer: None,
+                signal: Signal::Shutdown,
+            })
+            .await
+            .unwrap();
+        let ended = timeout(DEADLINE, async move {
+            tokio::task::spawn_blocking(move || pipeline.join())
+                .await
+                .unwrap()
+                .expect("pipeline thread panicked");
+            outgoing.await.unwrap();
+            signaling.await.unwrap().unwrap();
+        })
+        .await;
+        assert!(ended.is_ok(), "{} still running after {:?}", name, DEADLINE);
+    }
+}